@@ -0,0 +1,248 @@
+use super::*;
+
+/// Values the portfolio net of the taxes still owed on it, e.g. `{"future_rate": 0.22}`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AfterTax {
+    future_rate: f32,        // expected tax rate on traditional withdrawals
+    gains_rate: Option<f32>, // on embedded gains in taxable accounts, defaults to the qualified rate
+    #[serde(default)]
+    balance: bool, // balance to after-tax allocations, instead of pretax
+}
+
+impl AfterTax {
+    pub fn new(future_rate: f32, gains_rate: Option<f32>, balance: bool) -> Self {
+        AfterTax {
+            future_rate,
+            gains_rate,
+            balance,
+        }
+    }
+
+    pub fn is_valid(&self) -> bool {
+        [Some(self.future_rate), self.gains_rate]
+            .iter()
+            .flatten()
+            .all(|r| (0.0..=1.0).contains(r))
+    }
+}
+
+/// The portfolio valued after the taxes still owed on it
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct AfterTaxView {
+    value: Decimal,
+    allocations: BTreeMap<String, f32>, // by asset class, and cash
+    drift: BTreeMap<String, f32>,       // after-tax allocation of invested value minus the target
+}
+
+impl AfterTaxView {
+    /// Rounds the value to the cent
+    pub fn round(&mut self) {
+        self.value = money::cents(self.value);
+    }
+}
+
+/// How much of each dollar is left once the taxes owed on it are paid
+pub struct Discounts {
+    future_rate: f32,
+    gains_rate: f32,
+    balance: bool,
+}
+
+impl Discounts {
+    /// `None` unless the portfolio asked for an after-tax view
+    pub fn new(portfolio: &Portfolio) -> Option<Self> {
+        let after_tax = portfolio.after_tax.as_ref()?;
+        let qualified = portfolio.tax_rates.as_ref().map(|r| r.qualified());
+        Some(Discounts {
+            future_rate: after_tax.future_rate,
+            gains_rate: after_tax.gains_rate.or(qualified).unwrap_or(0.0),
+            balance: after_tax.balance,
+        })
+    }
+
+    /// Whether to balance to after-tax allocations
+    pub fn balance(&self) -> bool {
+        self.balance
+    }
+
+    /// Share left of cash, or newly bought shares, in the account
+    pub fn account(&self, account: &Account) -> f32 {
+        match account.account_type() {
+            AccountType::Traditional => 1.0 - self.future_rate,
+            _ => 1.0,
+        }
+    }
+
+    /// Share left of the account's holding of a fund, once any gains are taxed. Funds held as
+    /// plain share counts have an unknown basis, and so aren't discounted for gains.
+    pub fn holding(&self, results: &Results, account: &str, symbol: &str, price: Decimal) -> f32 {
+        let acct = match results.accounts.get(account) {
+            Some(a) => a,
+            None => return 1.0,
+        };
+        if acct.is_sheltered() {
+            return self.account(acct);
+        }
+        let lots = results.lots.get(account).and_then(|l| l.get(symbol));
+        let (value, gain) = lots
+            .into_iter()
+            .flatten()
+            .fold((Decimal::ZERO, Decimal::ZERO), |(v, g), l| {
+                (v + l.shares() * price, g + l.shares() * l.gain(price))
+            });
+        if value <= Decimal::ZERO {
+            return 1.0;
+        }
+        1.0 - self.gains_rate * money::fraction(gain, value)
+    }
+
+    /// After-tax value of each asset class, and of everything that can be invested
+    pub fn values(
+        &self,
+        results: &Results,
+        prices: &HashMap<&String, Decimal>,
+    ) -> (HashMap<String, Decimal>, Decimal) {
+        let mut values = HashMap::new();
+        let mut total = Decimal::ZERO;
+        for (account, positions) in results.positions.iter() {
+            for (sym, shares) in positions.iter() {
+                let price = *prices.get(sym).expect("unexpected missing price");
+                let discount = self.holding(results, account, sym, price);
+                let value = shares * price * money::decimal(discount);
+                let class = results.classes.get(sym).unwrap_or(sym);
+                *values.entry(class.clone()).or_insert(Decimal::ZERO) += value;
+                total += value;
+            }
+        }
+        total += self.cash(results, &results.cash);
+        (values, total)
+    }
+
+    fn cash(&self, results: &Results, cash: &BTreeMap<String, Decimal>) -> Decimal {
+        cash.iter()
+            .map(|(account, c)| match results.accounts.get(account) {
+                Some(a) => c * money::decimal(self.account(a)),
+                None => *c,
+            })
+            .sum()
+    }
+
+    /// Allocations and drift after taxes, like `Results::calculate_percentages`
+    pub fn view(&self, results: &Results, prices: &HashMap<&String, Decimal>) -> AfterTaxView {
+        let (values, invested) = self.values(results, prices);
+        let reserved = self.cash(results, &results.reserved);
+        let value = invested + reserved;
+
+        let mut drift = BTreeMap::new();
+        if invested > Decimal::ZERO {
+            for node in results.target.iter() {
+                let held: Decimal = node.leaves.iter().filter_map(|l| values.get(l)).sum();
+                drift.insert(
+                    node.name.clone(),
+                    money::fraction(held, invested) - node.weight,
+                );
+            }
+        }
+        let mut allocations = BTreeMap::new();
+        if value > Decimal::ZERO {
+            let cash = value - values.values().sum::<Decimal>();
+            for (class, held) in values.iter() {
+                allocations.insert(class.clone(), money::fraction(*held, value));
+            }
+            allocations.insert(String::from("cash"), money::fraction(cash, value));
+        }
+        AfterTaxView {
+            value,
+            allocations,
+            drift,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::accounts::test::check_shares;
+    use crate::run_balancing;
+    use spectral::prelude::*;
+
+    fn build_portfolio() -> Portfolio {
+        let mut p = Portfolio::new();
+        let mut taxed = Account::new("taxed");
+        let lots = vec![Lot::new(Date::new(2015, 1, 1), dec!(100), dec!(5))];
+        taxed
+            .positions
+            .insert(String::from("A"), Position::Lots(lots));
+        taxed.cash = dec!(4_000);
+        p.accounts.push(taxed);
+        let mut ira = Account::new("ira");
+        ira.tax_sheltered = true;
+        ira.cash = dec!(5_000);
+        p.accounts.push(ira);
+        p.target.insert(String::from("A"), 0.5.into());
+        p.target.insert(String::from("B"), 0.5.into());
+        p.market.push(Investment::new("A", dec!(10)));
+        p.market.push(Investment::new("B", dec!(100)));
+        p.after_tax = Some(AfterTax::new(0.4, Some(0.2), false));
+        p
+    }
+
+    #[test]
+    fn discount_holdings() {
+        let p = build_portfolio();
+        let d = Discounts::new(&p).unwrap();
+        let r = Results::from_positions(&p.accounts);
+
+        // half of A's value is gains, taxed at 20%
+        assert_that(&d.holding(&r, "taxed", "A", dec!(10))).is_close_to(0.9, 0.0001);
+        assert_that(&d.account(&p.accounts[1])).is_close_to(0.6, 0.0001);
+
+        let prices = c! { &i.symbol => i.price, for i in p.market.iter() };
+        let (values, total) = d.values(&r, &prices);
+        assert_that(&values["A"]).is_equal_to(dec!(900));
+        assert_that(&total).is_equal_to(dec!(900) + dec!(4_000) + dec!(3_000));
+        assert_that(&Discounts::new(&Portfolio::new()).is_none()).is_true();
+    }
+
+    #[test]
+    fn report_after_tax() {
+        let r = run_balancing(build_portfolio());
+
+        let view = r.after_tax.unwrap();
+        assert_that(&view.value).is_less_than(dec!(10_000));
+        let total: f32 = view.allocations.values().sum();
+        assert_that(&total).is_close_to(1.0, 0.0001);
+        assert_that(&view.drift.contains_key("A")).is_true();
+    }
+
+    #[test]
+    fn balance_after_tax() {
+        let mut p = build_portfolio();
+        p.after_tax = Some(AfterTax::new(0.4, Some(0.2), true));
+        p.accounts[1].allowed = Some(vec![String::from("B")].into_iter().collect());
+
+        let r = run_balancing(p);
+
+        // a dollar of B in the IRA is only worth 60 cents, so B is well over half pretax
+        let view = r.after_tax.clone().unwrap();
+        assert_that(&view.drift["A"]).is_close_to(0.0, 0.01);
+        assert_that(&r.drift["A"]).is_close_to(-0.09, 0.001);
+        check_shares(&r, "ira", "B", dec!(50));
+    }
+
+    #[test]
+    fn optimize_after_tax() {
+        let mut p = build_portfolio();
+        p.after_tax = Some(AfterTax::new(0.4, Some(0.2), true));
+        p.accounts[1].allowed = Some(vec![String::from("B")].into_iter().collect());
+        p.mode = Some(Mode::Optimize);
+
+        let r = run_balancing(p);
+
+        // the optimizer values the IRA's B at 60 cents on the dollar too
+        let view = r.after_tax.clone().unwrap();
+        assert_that(&view.drift["A"]).is_close_to(0.0, 0.01);
+        assert_that(&r.drift["A"]).is_close_to(-0.095, 0.001);
+        check_shares(&r, "ira", "B", dec!(50));
+    }
+}
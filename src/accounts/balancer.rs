@@ -1,21 +1,18 @@
 use super::*;
-use stats::median;
-use std::cmp::Ordering;
+use std::cmp::{Ordering, Reverse};
 use std::collections::BinaryHeap;
 
 #[derive(Debug)]
 struct Needed {
     symbol: String,
-    cash_delta: f32,
+    cash_delta: Decimal,
     percentage_delta: f32,
 }
 
 impl Needed {
-    fn new(symbol: &str, cash_delta: f32, portfolio: &Portfolio) -> Self {
-        let balanced_amount =
-            portfolio.target.get(symbol).expect("missing target") * portfolio.total_value();
-        let percentage_delta = if balanced_amount > 0.0 {
-            cash_delta / balanced_amount
+    fn new(symbol: &str, cash_delta: Decimal, balanced_amount: Decimal) -> Self {
+        let percentage_delta = if balanced_amount > Decimal::ZERO {
+            money::fraction(cash_delta, balanced_amount)
         } else {
             0.0
         };
@@ -31,96 +28,223 @@ impl Eq for Needed {}
 
 impl PartialOrd for Needed {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        self.percentage_delta.partial_cmp(&other.percentage_delta)
+        Some(self.cmp(other))
     }
 }
 
+/// Most needed first, and alphabetically between targets that are needed equally, so the
+/// heap pops them in the same order whatever order they were pushed in
 impl Ord for Needed {
     fn cmp(&self, other: &Self) -> Ordering {
-        self.partial_cmp(other).unwrap()
+        self.percentage_delta
+            .total_cmp(&other.percentage_delta)
+            .then_with(|| other.symbol.cmp(&self.symbol))
     }
 }
 
 impl PartialEq for Needed {
     fn eq(&self, other: &Self) -> bool {
-        self.percentage_delta == other.percentage_delta
+        self.cmp(other) == Ordering::Equal
     }
 }
 
-pub fn run_balancing(portfolio: Portfolio) -> Results {
-    let total_value = portfolio.total_value();
-    let allocations = c! { s => w * total_value, for (s, w) in portfolio.target.iter() };
-    let total_shares = portfolio.total_shares();
+pub fn run_balancing(mut portfolio: Portfolio) -> Results {
+    portfolio.deposit();
+    let target = portfolio.targets();
     // Portfolio::validate has already checked for the necessary prices
     let prices = c! { &i.symbol => i.price, for i in portfolio.market.iter() };
-    let yields = c! { &i.symbol => i.div_yield.unwrap_or(0.0), for i in portfolio.market.iter() };
-    let cash_delta = c! { s => a - total_shares.get(s).unwrap_or(&0.0) * prices.get(s).unwrap(),
-    for (s, a) in allocations };
-
-    let mut shares_delta = c! { s => d / prices.get(s).expect("missing price"),
-    for (s, d) in &cash_delta };
+    let placements = location::placements(&portfolio);
 
     let mut symbols_by_price = c![ (&i.symbol, i.price), for i in portfolio.market.iter() ];
     // price descending
-    symbols_by_price.sort_by(|(_, a), (_, b)| (b.round() as i32).cmp(&(a.round() as i32)));
+    symbols_by_price.sort_by(|(_, a), (_, b)| b.round().cmp(&a.round()));
     let symbols_by_price: Vec<&String> = symbols_by_price.iter().map(|(s, _)| *s).collect();
 
-    let median_yield = median(portfolio.market.iter().map(|i| i.div_yield.unwrap_or(0.0)));
-
     let mut accounts = portfolio.accounts.to_vec();
-    accounts.sort_by(|a, b| b.tax_sheltered.cmp(&a.tax_sheltered)); // sheltered accounts first
-    let taxable_first = {
+    // sheltered accounts first, and accounts with a limited menu get the first chance at the
+    // funds they can hold
+    accounts.sort_by_key(|a| (!a.is_sheltered(), a.allowed.is_none()));
+    // the accounts each kind of fund is best held in, e.g. bonds in traditional accounts
+    let placed = |placement: Placement| {
         let mut a = accounts.clone();
-        a.reverse();
+        a.sort_by_key(|a| (a.allowed.is_none(), a.account_type().preference(placement)));
         a
     };
+    let income_first = placed(Placement::Income);
+    let growth_first = placed(Placement::Growth);
+    let taxable_first = placed(Placement::Efficient);
+    let placement = |class: &str| {
+        placements
+            .get(class)
+            .cloned()
+            .unwrap_or(Placement::Efficient)
+    };
 
     let mut results = Results::from_positions(&accounts);
+    results.as_of = portfolio.as_of;
+    results.max_gains = portfolio.max_gains.clone();
+    results.min_trade = portfolio.min_trade;
+    results.max_trades = portfolio.max_trades;
+    results.quotes =
+        c! { i.symbol.clone() => (i.bid(), i.ask()), for i in portfolio.market.iter() };
+    results.target = target::nodes(&portfolio.target);
+    results.classes = portfolio
+        .classes
+        .iter()
+        .flat_map(|(c, members)| members.iter().map(move |s| (s.clone(), c.clone())))
+        .collect();
+    for account in accounts.iter() {
+        results.reserve_cash(&account.name, account.reserve(&portfolio.market));
+    }
+    // required distributions are taken out first, then the rest of the portfolio is balanced
+    for account in accounts.iter() {
+        if let Some(amount) = account.required_distribution {
+            withdrawal::raise_cash(&portfolio, &mut results, &prices, &[account], amount, true);
+        }
+    }
+
+    // values are net of taxes owed when balancing after tax, and otherwise pretax
+    let after_tax = Discounts::new(&portfolio).filter(|d| d.balance());
+    let (class_values, total_value) = match &after_tax {
+        Some(discounts) => discounts.values(&results, &prices),
+        None => (
+            results.class_values(&prices),
+            portfolio.investable_value() - results.withdrawn(),
+        ),
+    };
+    // after-tax value of a dollar sold from a holding, or bought in an account
+    let sell_factor = |r: &Results, account: &str, symbol: &str, price: Decimal| {
+        let factor = after_tax
+            .as_ref()
+            .map_or(1.0, |d| d.holding(r, account, symbol, price));
+        money::decimal(factor)
+    };
+    let buy_factor =
+        |account: &Account| money::decimal(after_tax.as_ref().map_or(1.0, |d| d.account(account)));
+    let allocations = c! { c => money::decimal(*w) * total_value, for (c, w) in target.iter() };
+    // value needed in each target class, negative if we're overweight
+    let mut cash_delta = c! { *c => a - class_values.get(*c).cloned().unwrap_or_default(),
+    for (c, a) in allocations.iter() };
 
     println!(
         "Accounts before action: {:?} with value {}",
         results.positions, total_value
     );
     println!("   disallowing sales: {:?}", &portfolio.no_sale_accounts);
-    println!("Shares delta before action: {:?}", shares_delta);
-    println!("Median yield={:?}, yields={:?}", median_yield, yields);
+    println!("Cash delta before action: {:?}", cash_delta);
+    println!("Placements: {:?}", placements);
+
+    if let Some(withdrawal) = &portfolio.withdrawal {
+        let from: Vec<&Account> = accounts
+            .iter()
+            .filter(|a| withdrawal.from_account(&a.name))
+            .collect();
+        withdrawal::raise_cash(
+            &portfolio,
+            &mut results,
+            &prices,
+            &from,
+            withdrawal.amount(),
+            false,
+        );
+        return finish(&portfolio, results, &prices);
+    }
 
-    // first sell shares we're overweight in
-    for (sym, delta) in shares_delta.iter_mut() {
-        if *delta > -1.0 {
-            continue;
+    // targets inside their tolerance bands aren't sold, though new cash still goes to whatever
+    // is most underweight
+    let out_of_band = tolerance::out_of_band(&portfolio, &target, &class_values, total_value);
+    let outside = out_of_band.as_deref();
+    for (class, delta) in cash_delta.iter_mut() {
+        if !tolerance::should_trade(outside, class) {
+            *delta = (*delta).max(Decimal::ZERO);
         }
-        println!("overweight in {}, selling {}", sym, delta);
-        let price = *prices.get(*sym).expect("unexpected missing price");
+    }
+    println!("out of band: {:?}", out_of_band);
+    results.out_of_band = out_of_band.clone().unwrap_or_default();
+    // the turnover cap only limits the sales made to rebalance, not those that paid for any
+    // required distributions, and is a share of what's left once they're taken out
+    let remaining = portfolio.total_value() - results.withdrawn();
+    results.max_turnover = portfolio
+        .max_turnover
+        .map(|t| money::decimal(t) / dec!(100) * remaining);
+
+    if portfolio.mode.unwrap_or_default() == Mode::Optimize {
+        optimizer::optimize(&portfolio, &mut results, &prices, outside);
+        return finish(&portfolio, results, &prices);
+    }
 
-        for account in accounts.iter() {
-            if !account.tax_sheltered && !portfolio.can_sell_taxed() {
-                continue;
-            }
-            if portfolio.no_sale_accounts.contains(&account.name) {
-                continue;
-            }
-            let acct_shares = results.transact(&account.name, sym, 0.0);
-            // positive number of shares to sell
-            let to_sell = if delta.abs().gt(&acct_shares) {
-                acct_shares
-            } else {
-                delta.abs().floor()
-            };
-            if to_sell < 1.0 {
+    // first sell shares we're overweight in, from whichever funds in the class are held. The
+    // most overweight go first, so a turnover cap cuts the sales that matter least.
+    let mut overweight: Vec<&String> = cash_delta
+        .iter()
+        .filter(|(_, d)| **d < Decimal::ZERO)
+        .map(|(c, _)| *c)
+        .collect();
+    overweight.sort_by(|a, b| cash_delta[a].cmp(&cash_delta[b]).then_with(|| a.cmp(b)));
+    let mut capped = HashSet::new();
+    for class in overweight {
+        let delta = cash_delta.get_mut(class).expect("missing overweight class");
+        println!("overweight in {}, selling ${}", class, delta);
+
+        // sell from sheltered accounts first, then from where the fund least belongs
+        let placement = placement(class);
+        let mut sellers = accounts.clone();
+        sellers.sort_by_key(|a| {
+            let preference = a.account_type().preference(placement);
+            (!a.is_sheltered(), a.allowed.is_none(), Reverse(preference))
+        });
+        for account in sellers.iter() {
+            if !portfolio.allows_sales(account) {
                 continue;
             }
+            for sym in portfolio.members(class) {
+                let price = *prices
+                    .get(&sym.to_string())
+                    .expect("unexpected missing price");
+                let acct_shares = results.transact(&account.name, sym, Decimal::ZERO);
+                let factor = sell_factor(&results, &account.name, sym, price);
+                // sales raise cash at the bid
+                let bid = results.fill_price(sym, price, -Decimal::ONE);
+                // a holding that's worth nothing after tax can all be sold
+                let wanted = (delta.abs() / bid)
+                    .checked_div(factor)
+                    .unwrap_or(acct_shares);
+                // positive number of shares to sell
+                let to_sell = if wanted.gt(&acct_shares) {
+                    acct_shares
+                } else {
+                    account.tradeable(wanted)
+                };
+                let to_sell = results.sellable(&account.name, sym, bid, to_sell);
+                let to_sell = match results.turnover_left() {
+                    Some(left) if to_sell * bid > left => {
+                        capped.insert(class);
+                        account.tradeable(left / bid)
+                    }
+                    _ => to_sell,
+                };
+                if to_sell < account.share_increment()
+                    || !results.worth_trading(&account.name, sym, price, -to_sell)
+                {
+                    continue;
+                }
 
-            if let Some(_) = results.buy_maybe(&account.name, sym, price, -1.0 * to_sell) {
-                *delta += to_sell;
-                println!(
-                    "In acct={} sold {} x {}@{}, fc={:?}. Remaining delta={}",
-                    account.name, to_sell, sym, price, &results.cash, delta
-                );
+                if let Some(_) = results.buy_maybe(&account.name, sym, price, -to_sell) {
+                    *delta += to_sell * bid * factor;
+                    println!(
+                        "In acct={} sold {} x {}@{}, fc={:?}. Remaining delta=${}",
+                        account.name, to_sell, sym, price, &results.cash, delta
+                    );
+                }
             }
         }
     }
 
+    for class in capped {
+        let drift = money::fraction(-cash_delta[class], total_value);
+        results.capped_drift.insert(class.clone(), drift);
+    }
+
     println!(
         "Results after sale of overweight positions: r={:?}",
         results
@@ -129,8 +253,8 @@ pub fn run_balancing(portfolio: Portfolio) -> Results {
     // now prepare to buy shares, in the order in which they're most needed
     println!("cash delta before buys: {:?}", &cash_delta);
     let mut needed_funds = BinaryHeap::new();
-    for (sym, value_needed) in cash_delta.into_iter() {
-        needed_funds.push(Needed::new(sym, value_needed, &portfolio));
+    for (class, value_needed) in cash_delta.into_iter() {
+        needed_funds.push(Needed::new(class, value_needed, allocations[class]));
     }
 
     println!("needed heap before start: {:?}", needed_funds);
@@ -140,51 +264,81 @@ pub fn run_balancing(portfolio: Portfolio) -> Results {
             Some(n) => n,
             None => break,
         };
-        let symbol = &next.symbol;
-        let price = *prices.get(symbol).expect("unexpected missing price");
-        let shares = next.cash_delta / price;
-        if shares <= 0.0 {
+        if next.cash_delta <= Decimal::ZERO {
             continue;
         }
+        let class = &next.symbol;
+        let mut spent = Decimal::ZERO;
+        // the fund in the class each account would buy, and how many shares the cash it needs
+        // buys at the ask
+        let order = |results: &Results, account: &Account| {
+            let symbol = portfolio.fund_for(account, class)?;
+            let price = *prices
+                .get(&symbol.to_string())
+                .expect("unexpected missing price");
+            let ask = results.fill_price(symbol, price, Decimal::ONE);
+            let shares = (next.cash_delta / ask).checked_div(buy_factor(account));
+            Some((symbol, price, shares.unwrap_or_default()))
+        };
 
-        let mut bought = false;
-
-        // if the fund is 'high yield', try to allocate into a tax-sheltered account
-        let is_high_yield = match yields.get(symbol) {
-            Some(div_yield) => match median_yield {
-                Some(median) if *div_yield as f64 > median => true, // success!
-                _ => false,
-            },
-            _ => false,
+        // try the accounts the fund is better held in than taxable first, e.g. sheltered
+        // accounts for funds with a lot of tax drag
+        let placement = placement(class);
+        let preferred = |a: &&Account| {
+            let taxable = AccountType::Taxable.preference(placement);
+            a.account_type().preference(placement) < taxable
         };
-        if is_high_yield {
-            for account in accounts.iter().filter(|a| a.tax_sheltered) {
-                if let Some(_) = results.buy_maybe(&account.name, symbol, price, 1.0) {
-                    bought = true;
-                    println!(
-                        "high-yield: acct={}, bought {}@{}, fc={:?}, diff={:.2}%",
-                        account.name,
-                        symbol,
-                        price,
-                        results.cash,
-                        next.percentage_delta * 100.0,
-                    );
-                    break;
-                }
+        let best_first = match placement {
+            Placement::Income => &income_first,
+            Placement::Growth => &growth_first,
+            Placement::Efficient => &taxable_first,
+        };
+        for account in best_first.iter().filter(preferred) {
+            let (symbol, price, shares) = match order(&results, account) {
+                Some(order) => order,
+                None => continue,
+            };
+            let quantity = results.buy_quantity(account, symbol, price, shares);
+            if quantity <= Decimal::ZERO
+                || !worth_buying(&results, account, symbol, price, shares, quantity)
+            {
+                continue;
+            }
+            if let Some(gross) = results.buy_maybe(&account.name, symbol, price, quantity) {
+                spent = gross * buy_factor(account);
+                println!(
+                    "{:?}: acct={}, bought {} x {}@{}, fc={:?}, diff={:.2}%",
+                    placement,
+                    account.name,
+                    quantity,
+                    symbol,
+                    price,
+                    results.cash,
+                    next.percentage_delta * 100.0,
+                );
+                break;
             }
         }
         // otherwise just try to put it into the first account it fits into
-        if !bought {
-            // don't check the tax-sheltered accounts again if we already have
-            for account in taxable_first
-                .iter()
-                .filter(|a| !is_high_yield || !a.tax_sheltered)
-            {
-                if let Some(_) = results.buy_maybe(&account.name, symbol, price, 1.0) {
-                    bought = true;
+        if spent <= Decimal::ZERO {
+            // don't check the preferred accounts again
+            for account in taxable_first.iter().filter(|a| !preferred(a)) {
+                let (symbol, price, shares) = match order(&results, account) {
+                    Some(order) => order,
+                    None => continue,
+                };
+                let quantity = results.buy_quantity(account, symbol, price, shares);
+                if quantity <= Decimal::ZERO
+                    || !worth_buying(&results, account, symbol, price, shares, quantity)
+                {
+                    continue;
+                }
+                if let Some(gross) = results.buy_maybe(&account.name, symbol, price, quantity) {
+                    spent = gross * buy_factor(account);
                     println!(
-                        "acct={}, bought {}@{}, fc={:?}, diff={:.2}%",
+                        "acct={}, bought {} x {}@{}, fc={:?}, diff={:.2}%",
                         account.name,
+                        quantity,
                         symbol,
                         price,
                         results.cash,
@@ -195,10 +349,11 @@ pub fn run_balancing(portfolio: Portfolio) -> Results {
             }
         }
 
-        if bought {
-            let new_needed = next.cash_delta - price;
-            if new_needed > 0.0 {
-                needed_funds.push(Needed::new(&next.symbol, new_needed, &portfolio));
+        if spent > Decimal::ZERO {
+            let new_needed = next.cash_delta - spent;
+            if new_needed > Decimal::ZERO {
+                let balanced_amount = allocations[&next.symbol];
+                needed_funds.push(Needed::new(&next.symbol, new_needed, balanced_amount));
             }
         }
     }
@@ -211,11 +366,19 @@ pub fn run_balancing(portfolio: Portfolio) -> Results {
         for sym in symbols_by_price.iter() {
             let price = *prices.get(*sym).expect("unexpected missing price");
             for account in accounts.iter() {
-                if let Some(_) = results.buy_maybe(&account.name, sym, price, 1.0) {
+                let quantity = results.buy_quantity(account, sym, price, Decimal::ONE);
+                // extra shares don't fix any drift, so aren't worth a commission
+                if quantity <= Decimal::ZERO
+                    || results.commission(&account.name, sym, quantity) > Decimal::ZERO
+                    || !results.worth_trading(&account.name, sym, price, quantity)
+                {
+                    continue;
+                }
+                if let Some(_) = results.buy_maybe(&account.name, sym, price, quantity) {
                     bought = true;
                     println!(
-                        "extra: acct={}, bought {}@{}, fc={:?}",
-                        account.name, sym, price, results.cash
+                        "extra: acct={}, bought {} x {}@{}, fc={:?}",
+                        account.name, quantity, sym, price, results.cash
                     );
                     break; // we found an account to hold the extra share, move on to next fund
                 }
@@ -227,14 +390,44 @@ pub fn run_balancing(portfolio: Portfolio) -> Results {
         }
     }
 
-    results.calculate_percentages(&prices);
+    finish(&portfolio, results, &prices)
+}
+
+/// Whether the whole order the account would place for the fund is worth its commission,
+/// rather than just the next share of it
+fn worth_buying(
+    results: &Results,
+    account: &Account,
+    symbol: &str,
+    price: Decimal,
+    wanted: Decimal,
+    quantity: Decimal,
+) -> bool {
+    let ask = results.fill_price(symbol, price, Decimal::ONE);
+    let affordable = results.available_cash(&account.name) / ask;
+    let order = account.tradeable(wanted.min(affordable)).max(quantity);
+    results.worth_trading(&account.name, symbol, price, order)
+}
+
+/// Everything that's done with the balanced positions, whichever balancer produced them
+fn finish(
+    portfolio: &Portfolio,
+    mut results: Results,
+    prices: &HashMap<&String, Decimal>,
+) -> Results {
+    results.merge_small_trades();
+    results.harvests = harvest::find_harvests(portfolio, &results, prices);
+    results.order_trades(&portfolio.accounts);
+    results.calculate_percentages(prices);
+    results.after_tax = Discounts::new(portfolio).map(|d| d.view(&results, prices));
+    results.round();
     println!("Results after balancing: {:?}", results);
     results
 }
 
 #[cfg(test)]
 mod single_account {
-    use super::test::check_allocation;
+    use super::test::{check_allocation, check_shares};
     use super::*;
     use spectral::prelude::*;
     use std::ops::IndexMut;
@@ -247,12 +440,12 @@ mod single_account {
     fn build_portfolio() -> Portfolio {
         let mut p = Portfolio::new();
         let mut acct = Account::new("taxed");
-        acct.cash = 10_000.0;
+        acct.cash = dec!(10_000);
         p.accounts.push(acct);
-        p.target.insert(String::from("A"), 0.5);
-        p.target.insert(String::from("B"), 0.5);
-        p.market.push(Investment::new("A", 10.0));
-        p.market.push(Investment::new("B", 100.0));
+        p.target.insert(String::from("A"), 0.5.into());
+        p.target.insert(String::from("B"), 0.5.into());
+        p.market.push(Investment::new("A", dec!(10)));
+        p.market.push(Investment::new("B", dec!(100)));
         p
     }
 
@@ -264,21 +457,21 @@ mod single_account {
 
         let r = run_balancing(p);
 
-        assert_that(&r.total_cash).is_close_to(0.0, 0.1);
-        check_shares(&r, "taxed", "A", 500.0);
-        check_shares(&r, "taxed", "B", 50.0);
+        assert_that(&r.total_cash).is_equal_to(dec!(0));
+        check_shares(&r, "taxed", "A", dec!(500));
+        check_shares(&r, "taxed", "B", dec!(50));
     }
 
     #[test]
     fn simple_extra_cash() {
         let mut p = build_portfolio();
-        p.accounts.index_mut(0).cash += 5.0;
+        p.accounts.index_mut(0).cash += dec!(5);
 
         let r = run_balancing(p);
 
-        assert_that(&r.total_cash).is_close_to(5.0, 0.1);
-        check_shares(&r, "taxed", "A", 500.0);
-        check_shares(&r, "taxed", "B", 50.0);
+        assert_that(&r.total_cash).is_equal_to(dec!(5));
+        check_shares(&r, "taxed", "A", dec!(500));
+        check_shares(&r, "taxed", "B", dec!(50));
         check_allocation(&r, "A", 0.499);
         check_allocation(&r, "B", 0.499);
         check_allocation(&r, "cash", 0.001);
@@ -287,41 +480,35 @@ mod single_account {
     #[test]
     fn simple_cash_neeed() {
         let mut p = build_portfolio();
-        p.accounts.index_mut(0).cash = -5_000.0;
+        p.accounts.index_mut(0).cash = dec!(-5_000);
         p.accounts
             .index_mut(0)
             .positions
-            .insert("A".to_string(), 500.0);
+            .insert("A".to_string(), dec!(500).into());
         p.accounts
             .index_mut(0)
             .positions
-            .insert("A".to_string(), 500.0);
+            .insert("A".to_string(), dec!(500).into());
         p.accounts
             .index_mut(0)
             .positions
-            .insert("B".to_string(), 50.0);
+            .insert("B".to_string(), dec!(50).into());
 
         let r = run_balancing(p);
 
-        assert_that(&r.total_cash).is_close_to(0.0, 0.1);
+        assert_that(&r.total_cash).is_equal_to(dec!(0));
         check_allocation(&r, "A", 0.499);
         check_allocation(&r, "B", 0.499);
         check_allocation(&r, "cash", 0.001);
     }
 
-    pub fn check_shares(r: &Results, acct: &str, sym: &str, expected: f32) {
-        let account = r.positions.get(acct).expect("missing account");
-        let shares = account.get(sym);
-        assert_that(shares.unwrap_or(&0.0)).is_close_to(expected, 0.1);
-    }
-
     fn build_sale_needed_portfolio() -> Portfolio {
         let mut p = build_portfolio();
         {
             let taxed = p.accounts.index_mut(0);
             // 100% B and no cash, will need to sell half to buy A
-            taxed.cash = 0.0;
-            taxed.positions.insert(String::from("B"), 100.0);
+            taxed.cash = dec!(0);
+            taxed.positions.insert(String::from("B"), dec!(100).into());
         }
         p
     }
@@ -332,22 +519,62 @@ mod single_account {
 
         let r = run_balancing(p);
 
-        assert_that(&r.total_cash).is_close_to(0.0, 0.1);
-        check_shares(&r, "taxed", "A", 500.0);
-        check_shares(&r, "taxed", "B", 50.0);
+        assert_that(&r.total_cash).is_equal_to(dec!(0));
+        check_shares(&r, "taxed", "A", dec!(500));
+        check_shares(&r, "taxed", "B", dec!(50));
+
+        assert_that(&r.trades).has_length(2);
+        assert_that(&r.trades[0]).is_equal_to(Trade::new("taxed", "B", dec!(100), dec!(-50)));
+        assert_that(&r.trades[1]).is_equal_to(Trade::new("taxed", "A", dec!(10), dec!(500)));
     }
 
     #[test]
     fn no_fractional_sales() {
         let mut p = build_sale_needed_portfolio();
-        p.target.insert(String::from("A"), 0.34);
-        p.target.insert(String::from("B"), 0.66);
+        // B is 33.5 shares over target, but only whole shares are sold
+        p.target.insert(String::from("A"), 0.335.into());
+        p.target.insert(String::from("B"), 0.665.into());
+
+        let r = run_balancing(p);
+
+        assert_that(&r.total_cash).is_equal_to(dec!(0));
+        check_shares(&r, "taxed", "A", dec!(330));
+        check_shares(&r, "taxed", "B", dec!(67));
+    }
+
+    #[test]
+    fn fractional_shares() {
+        let mut p = build_portfolio();
+        {
+            let a = p.accounts.index_mut(0);
+            a.cash = dec!(1_005);
+            a.fractional_shares = Some(true);
+        }
+        p.market.index_mut(1).price = dec!(300);
+
+        let r = run_balancing(p);
+
+        // $502.50 in each, which is 1.675 shares of B
+        assert_that(&r.total_cash).is_equal_to(dec!(0));
+        check_shares(&r, "taxed", "A", dec!(50.25));
+        assert_that(r.positions.get("taxed").unwrap().get("B").unwrap()).is_equal_to(dec!(1.675));
+        check_allocation(&r, "A", 0.5);
+        check_allocation(&r, "B", 0.5);
+        assert_that(&r.trades).has_length(2);
+    }
+
+    #[test]
+    fn fractional_sales() {
+        let mut p = build_sale_needed_portfolio();
+        p.accounts.index_mut(0).fractional_shares = Some(true);
+        p.target.insert(String::from("A"), 0.335.into());
+        p.target.insert(String::from("B"), 0.665.into());
 
         let r = run_balancing(p);
 
-        assert_that(&r.total_cash).is_close_to(0.0, 0.1);
-        check_shares(&r, "taxed", "A", 330.0);
-        check_shares(&r, "taxed", "B", 67.0);
+        assert_that(&r.total_cash).is_equal_to(dec!(0));
+        check_shares(&r, "taxed", "A", dec!(335));
+        check_shares(&r, "taxed", "B", dec!(66.5));
     }
 
     #[test]
@@ -357,8 +584,8 @@ mod single_account {
 
         let r = run_balancing(p);
 
-        assert_that(&r.total_cash).is_close_to(0.0, 0.1);
-        check_shares(&r, "taxed", "B", 100.0);
+        assert_that(&r.total_cash).is_equal_to(dec!(0));
+        check_shares(&r, "taxed", "B", dec!(100));
     }
 
     #[test]
@@ -368,8 +595,8 @@ mod single_account {
 
         let r = run_balancing(p);
 
-        assert_that(&r.total_cash).is_close_to(0.0, 0.1);
-        check_shares(&r, "taxed", "B", 100.0);
+        assert_that(&r.total_cash).is_equal_to(dec!(0));
+        check_shares(&r, "taxed", "B", dec!(100));
     }
 
     #[test]
@@ -384,33 +611,73 @@ mod single_account {
 
         let r = run_balancing(p);
 
-        assert_that(&r.total_cash).is_close_to(0.0, 0.1);
-        check_shares(&r, "ira", "A", 500.0);
-        check_shares(&r, "ira", "B", 50.0);
+        assert_that(&r.total_cash).is_equal_to(dec!(0));
+        check_shares(&r, "ira", "A", dec!(500));
+        check_shares(&r, "ira", "B", dec!(50));
         check_allocation(&r, "A", 0.5);
         check_allocation(&r, "B", 0.5);
         check_allocation(&r, "cash", 0.0);
     }
 
+    fn build_gains_portfolio() -> Portfolio {
+        let mut p = build_sale_needed_portfolio();
+        p.as_of = Some(Date::new(2020, 6, 1));
+        let lots = vec![
+            Lot::new(Date::new(2018, 1, 1), dec!(70), dec!(50)), // $50/share gain
+            Lot::new(Date::new(2019, 1, 1), dec!(30), dec!(100)), // no gain
+        ];
+        p.accounts
+            .index_mut(0)
+            .positions
+            .insert(String::from("B"), Position::Lots(lots));
+        p
+    }
+
+    #[test]
+    fn sale_within_gains_budget() {
+        let mut p = build_gains_portfolio();
+        p.accounts.index_mut(0).max_gains = Some(GainsBudget::new(Some(dec!(500)), None, None));
+
+        let r = run_balancing(p);
+
+        // the 30 shares without gains go first, then 10 more use up the budget
+        assert_that(&r.total_cash).is_equal_to(dec!(0));
+        check_shares(&r, "taxed", "B", dec!(60));
+        check_shares(&r, "taxed", "A", dec!(400));
+        assert_that(&r.gains.get("taxed")).is_equal_to(Some(&Gains::new(dec!(0), dec!(500))));
+        assert_that(&r.trades[0].lots).has_length(2);
+    }
+
+    #[test]
+    fn portfolio_gains_budget() {
+        let mut p = build_gains_portfolio();
+        p.max_gains = Some(GainsBudget::new(None, None, Some(dec!(0))));
+
+        let r = run_balancing(p);
+
+        check_shares(&r, "taxed", "B", dec!(70));
+        assert_that(&r.gains.get("taxed")).is_equal_to(Some(&Gains::new(dec!(0), dec!(0))));
+    }
+
     #[test]
     fn minimize_spare_cash() {
         let mut p = build_portfolio();
         {
             let a = p.accounts.index_mut(0);
-            a.cash = 507.0;
-            p.target.insert(String::from("C"), 0.0);
-            p.market.push(Investment::new("C", 1.0));
+            a.cash = dec!(507);
+            p.target.insert(String::from("C"), 0.0.into());
+            p.market.push(Investment::new("C", dec!(1)));
         }
 
         let r = run_balancing(p);
 
-        // overweight in B shares, we buy one we don't need all of
-        assert_that(&r.total_cash).is_close_to(0.0, 0.1);
-        check_shares(&r, "taxed", "A", 20.0);
-        check_shares(&r, "taxed", "B", 3.0);
-        check_shares(&r, "taxed", "C", 7.0);
-        check_allocation(&r, "A", 0.394);
-        check_allocation(&r, "B", 0.592);
+        // A and B are needed equally, so A goes first and the last B share doesn't fit
+        assert_that(&r.total_cash).is_equal_to(dec!(0));
+        check_shares(&r, "taxed", "A", dec!(30));
+        check_shares(&r, "taxed", "B", dec!(2));
+        check_shares(&r, "taxed", "C", dec!(7));
+        check_allocation(&r, "A", 0.592);
+        check_allocation(&r, "B", 0.394);
         check_allocation(&r, "C", 0.014);
     }
 
@@ -418,67 +685,289 @@ mod single_account {
     fn buys_most_needed_first() {
         let mut p = Portfolio::new();
         let mut acct = Account::new("taxed");
-        acct.cash = 20.0; // not enough cash to fully balance
-        acct.positions.insert(String::from("A"), 55.0);
-        acct.positions.insert(String::from("B"), 25.0);
-        acct.positions.insert(String::from("C"), 0.0);
+        acct.cash = dec!(20); // not enough cash to fully balance
+        acct.positions.insert(String::from("A"), dec!(55).into());
+        acct.positions.insert(String::from("B"), dec!(25).into());
+        acct.positions.insert(String::from("C"), dec!(0).into());
         p.accounts.push(acct);
         p.no_sale_accounts.insert(String::from("taxed"));
-        p.target.insert(String::from("A"), 0.33);
-        p.target.insert(String::from("B"), 0.33);
-        p.target.insert(String::from("C"), 0.34);
+        p.target.insert(String::from("A"), 0.33.into());
+        p.target.insert(String::from("B"), 0.33.into());
+        p.target.insert(String::from("C"), 0.34.into());
 
-        p.market.push(Investment::new("A", 1.0));
-        p.market.push(Investment::new("B", 1.0));
-        p.market.push(Investment::new("C", 1.0));
+        p.market.push(Investment::new("A", dec!(1)));
+        p.market.push(Investment::new("B", dec!(1)));
+        p.market.push(Investment::new("C", dec!(1)));
 
-        assert_that(&p.total_value()).is_close_to(100.0, 0.1);
+        assert_that(&p.total_value()).is_equal_to(dec!(100));
 
         let r = run_balancing(p);
 
         // total value is 100, so an even balance would be ~33 each
         // but we can't get to that because too much A and no sales allowed
         // make sure we don't buy any B because we need C more
-        assert_that(&r.total_cash).is_close_to(0.0, 0.1);
-        check_shares(&r, "taxed", "A", 55.0);
-        check_shares(&r, "taxed", "B", 25.0);
-        check_shares(&r, "taxed", "C", 20.0);
+        assert_that(&r.total_cash).is_equal_to(dec!(0));
+        check_shares(&r, "taxed", "A", dec!(55));
+        check_shares(&r, "taxed", "B", dec!(25));
+        check_shares(&r, "taxed", "C", dec!(20));
+    }
+
+    #[test]
+    fn deterministic_results() {
+        let build = || {
+            let mut p = Portfolio::new();
+            let mut acct = Account::new("taxed");
+            acct.cash = dec!(10); // only enough for half the shares that are needed
+            p.accounts.push(acct);
+            for symbol in ["D", "B", "A", "C"] {
+                p.target.insert(String::from(symbol), 0.25.into());
+                p.market.push(Investment::new(symbol, dec!(5)));
+            }
+            p
+        };
+
+        let r = run_balancing(build());
+
+        // every target is needed equally, so they're bought in alphabetical order
+        check_shares(&r, "taxed", "A", dec!(1));
+        check_shares(&r, "taxed", "B", dec!(1));
+        let json = serde_json::to_string(&r).unwrap();
+        assert_that(&json.find(r#""A":0.5"#)).is_less_than(json.find(r#""B":0.5"#));
+        for _ in 0..10 {
+            let again = serde_json::to_string(&run_balancing(build())).unwrap();
+            assert_that(&again).is_equal_to(&json);
+        }
     }
 
     #[test]
     fn buys_percentage_needed() {
         let mut p = Portfolio::new();
         let mut acct = Account::new("taxed");
-        acct.cash = 3.0; // not enough cash to fully balance
-        acct.positions.insert(String::from("A"), 88.0);
-        acct.positions.insert(String::from("B"), 7.0);
-        acct.positions.insert(String::from("C"), 0.0);
+        acct.cash = dec!(3); // not enough cash to fully balance
+        acct.positions.insert(String::from("A"), dec!(88).into());
+        acct.positions.insert(String::from("B"), dec!(7).into());
+        acct.positions.insert(String::from("C"), dec!(0).into());
         p.accounts.push(acct);
         p.no_sale_accounts.insert(String::from("taxed"));
-        p.target.insert(String::from("A"), 0.90);
-        p.target.insert(String::from("B"), 0.08);
-        p.target.insert(String::from("C"), 0.02);
+        p.target.insert(String::from("A"), 0.90.into());
+        p.target.insert(String::from("B"), 0.08.into());
+        p.target.insert(String::from("C"), 0.02.into());
 
-        p.market.push(Investment::new("A", 1.0));
-        p.market.push(Investment::new("B", 1.0));
-        p.market.push(Investment::new("C", 1.0));
+        p.market.push(Investment::new("A", dec!(1)));
+        p.market.push(Investment::new("B", dec!(1)));
+        p.market.push(Investment::new("C", dec!(1)));
 
         let r = run_balancing(p);
 
         // we need $2 more A, $1 more B and $2 C
         // so naively we'd buy A and C
         // but it's 100% more C and ~2% more A so instead we buy B
-        assert_that(&r.total_cash).is_close_to(0.0, 0.1);
-        check_shares(&r, "taxed", "A", 88.0);
-        check_shares(&r, "taxed", "B", 8.0);
-        check_shares(&r, "taxed", "C", 2.0);
+        assert_that(&r.total_cash).is_equal_to(dec!(0));
+        check_shares(&r, "taxed", "A", dec!(88));
+        check_shares(&r, "taxed", "B", dec!(8));
+        check_shares(&r, "taxed", "C", dec!(2));
+    }
+
+    #[test]
+    fn pay_commissions() {
+        let mut p = build_portfolio();
+        p.accounts.index_mut(0).commission = Some(Commission::new(dec!(5), dec!(0)));
+
+        let r = run_balancing(p);
+
+        // one commission for each fund, however many shares are bought
+        assert_that(&r.trades).has_length(2);
+        assert_that(&r.commissions).is_equal_to(dec!(10));
+        let spent: Decimal = r.trades.iter().map(|t| t.gross + t.commission).sum();
+        assert_that(&(spent + r.total_cash)).is_equal_to(dec!(10_000));
+    }
+
+    #[test]
+    fn buy_at_the_ask() {
+        let mut p = build_portfolio();
+        p.market.index_mut(0).ask = Some(dec!(10.1));
+        p.market.index_mut(1).ask = Some(dec!(101));
+
+        let r = run_balancing(p);
+
+        // every buy is paid for at the ask, so the cash never runs short
+        assert_that(&r.total_cash).is_greater_than_or_equal_to(dec!(0));
+        let spent: Decimal = r.trades.iter().map(|t| t.gross).sum();
+        assert_that(&(spent + r.total_cash)).is_equal_to(dec!(10_000));
+        for trade in r.trades.iter() {
+            let ask = if trade.symbol == "A" {
+                dec!(10.1)
+            } else {
+                dec!(101)
+            };
+            assert_that(&trade.price).is_equal_to(ask);
+            assert_that(&trade.limit).is_equal_to(ask);
+        }
+    }
+
+    #[test]
+    fn sell_at_the_bid() {
+        let mut p = build_portfolio();
+        p.accounts.index_mut(0).cash = dec!(0);
+        p.accounts
+            .index_mut(0)
+            .positions
+            .insert(String::from("B"), dec!(100).into());
+        p.market.index_mut(1).bid = Some(dec!(99));
+
+        let r = run_balancing(p);
+
+        let sale = r.trades.iter().find(|t| t.action == Action::Sell).unwrap();
+        assert_that(&sale.symbol.as_str()).is_equal_to("B");
+        assert_that(&sale.shares).is_equal_to(dec!(50));
+        assert_that(&sale.limit).is_equal_to(dec!(99));
+        assert_that(&sale.gross).is_equal_to(dec!(4_950));
+        // the proceeds buy 495 shares of A, not the 500 the last price would suggest
+        check_shares(&r, "taxed", "A", dec!(495));
+        assert_that(&r.total_cash).is_greater_than_or_equal_to(dec!(0));
+
+        // the turnover cap counts sales at the bid too, so $1k of it sells 11 shares
+        let mut p = build_portfolio();
+        p.accounts.index_mut(0).cash = dec!(0);
+        p.accounts
+            .index_mut(0)
+            .positions
+            .insert(String::from("B"), dec!(100).into());
+        p.market.index_mut(1).bid = Some(dec!(90));
+        p.max_turnover = Some(10.0);
+
+        let r = run_balancing(p);
+
+        check_shares(&r, "taxed", "B", dec!(89));
+        let sale = r.trades.iter().find(|t| t.action == Action::Sell).unwrap();
+        assert_that(&sale.gross).is_equal_to(dec!(990));
+    }
+
+    fn build_commission_portfolio() -> Portfolio {
+        let mut p = Portfolio::new();
+        let mut acct = Account::new("taxed");
+        acct.cash = dec!(8);
+        acct.positions.insert(String::from("A"), dec!(200).into());
+        acct.positions.insert(String::from("B"), dec!(10).into());
+        acct.commission = Some(Commission::new(dec!(10), dec!(0)));
+        p.accounts.push(acct);
+        p.target.insert(String::from("A"), 0.5.into());
+        p.target.insert(String::from("B"), 0.5.into());
+        p.market.push(Investment::new("A", dec!(5)));
+        p.market.push(Investment::new("B", dec!(100)));
+        p
+    }
+
+    #[test]
+    fn skip_trades_costing_more_than_drift() {
+        let r = run_balancing(build_commission_portfolio());
+
+        // a $5 share of A isn't worth a $10 commission
+        assert_that(&r.trades).is_empty();
+        assert_that(&r.total_cash).is_equal_to(dec!(8));
+
+        let mut p = build_commission_portfolio();
+        p.accounts
+            .index_mut(0)
+            .commission_free
+            .insert(String::from("A"));
+        let r = run_balancing(p);
+        check_shares(&r, "taxed", "A", dec!(201));
+        assert_that(&r.commissions).is_equal_to(dec!(0));
+    }
+
+    #[test]
+    fn minimum_trade_size() {
+        let mut p = Portfolio::new();
+        let mut acct = Account::new("taxed");
+        acct.cash = dec!(150);
+        acct.positions.insert(String::from("A"), dec!(95).into());
+        acct.positions.insert(String::from("B"), dec!(9).into());
+        p.accounts.push(acct);
+        p.target.insert(String::from("A"), 0.5.into());
+        p.target.insert(String::from("B"), 0.5.into());
+        p.market.push(Investment::new("A", dec!(10)));
+        p.market.push(Investment::new("B", dec!(100)));
+        p.min_trade = Some(dec!(100));
+
+        let r = run_balancing(p);
+
+        // the $50 of A that's needed is too small to bother with
+        assert_that(&r.trades).has_length(1);
+        check_shares(&r, "taxed", "A", dec!(95));
+        check_shares(&r, "taxed", "B", dec!(10));
+        assert_that(&r.total_cash).is_equal_to(dec!(50));
+    }
+
+    #[test]
+    fn turnover_cap() {
+        let mut p = Portfolio::new();
+        let mut acct = Account::new("taxed");
+        acct.positions.insert(String::from("B"), dec!(45).into());
+        acct.positions.insert(String::from("C"), dec!(55).into());
+        p.accounts.push(acct);
+        p.target.insert(String::from("A"), 0.2.into());
+        p.target.insert(String::from("B"), 0.3.into());
+        p.target.insert(String::from("C"), 0.5.into());
+        p.market.push(Investment::new("A", dec!(10)));
+        p.market.push(Investment::new("B", dec!(100)));
+        p.market.push(Investment::new("C", dec!(100)));
+        p.max_turnover = Some(10.0);
+
+        let r = run_balancing(p);
+
+        // B is the most overweight, so it uses up the whole $1k of sales
+        assert_that(&r.turnover).is_close_to(0.1, 0.0001);
+        check_shares(&r, "taxed", "A", dec!(100));
+        check_shares(&r, "taxed", "B", dec!(35));
+        check_shares(&r, "taxed", "C", dec!(55));
+        assert_that(&r.capped_drift["B"]).is_close_to(0.05, 0.0001);
+        assert_that(&r.capped_drift["C"]).is_close_to(0.05, 0.0001);
+    }
+
+    #[test]
+    fn turnover_cap_after_distribution() {
+        let mut p = Portfolio::new();
+        let mut ira = Account::new("ira");
+        ira.account_type = Some(AccountType::Traditional);
+        ira.positions.insert(String::from("B"), dec!(45).into());
+        ira.positions.insert(String::from("C"), dec!(55).into());
+        ira.required_distribution = Some(dec!(1_000));
+        p.accounts.push(ira);
+        p.target.insert(String::from("A"), 0.2.into());
+        p.target.insert(String::from("B"), 0.3.into());
+        p.target.insert(String::from("C"), 0.5.into());
+        p.market.push(Investment::new("A", dec!(10)));
+        p.market.push(Investment::new("B", dec!(100)));
+        p.market.push(Investment::new("C", dec!(100)));
+        p.max_turnover = Some(10.0);
+
+        let r = run_balancing(p);
+
+        // the $1k distribution comes out of B, and then 10% of the $9k left can be rebalanced
+        assert_that(&r.withdrawals["ira"]).is_equal_to(dec!(1_000));
+        assert_that(&r.turnover).is_close_to(0.1, 0.0001);
+        check_shares(&r, "ira", "A", dec!(90));
+        // $1.8k is overweight after the distribution, and $900 of it can't be sold
+        assert_that(&r.capped_drift.values().sum::<f32>()).is_close_to(0.1, 0.0001);
+    }
+
+    #[test]
+    fn limit_trade_count() {
+        let mut p = build_portfolio();
+        p.max_trades = Some(1);
+
+        let r = run_balancing(p);
+
+        // all the cash goes into one order
+        assert_that(&r.trades).has_length(1);
+        assert_that(&r.total_cash).is_less_than(dec!(100));
     }
 }
 
 #[cfg(test)]
 mod multiple_accounts {
-    use super::single_account::check_shares;
-    use super::test::check_allocation;
+    use super::test::{check_allocation, check_shares};
     use super::*;
     use spectral::prelude::*;
     use std::ops::IndexMut;
@@ -486,32 +975,32 @@ mod multiple_accounts {
     fn build_multi_portfolio() -> Portfolio {
         let mut p = Portfolio::new();
         let mut taxed = Account::new("taxed");
-        taxed.cash = 8_000.0;
+        taxed.cash = dec!(8_000);
         p.accounts.push(taxed);
         let mut ira = Account::new("ira");
-        ira.cash = 2_000.0;
+        ira.cash = dec!(2_000);
         ira.tax_sheltered = true;
         p.accounts.push(ira);
-        p.target.insert(String::from("A"), 0.5);
-        p.target.insert(String::from("B"), 0.5);
-        p.market.push(Investment::new("A", 10.0));
-        p.market.push(Investment::new("B", 100.0));
+        p.target.insert(String::from("A"), 0.5.into());
+        p.target.insert(String::from("B"), 0.5.into());
+        p.market.push(Investment::new("A", dec!(10)));
+        p.market.push(Investment::new("B", dec!(100)));
         p
     }
 
     #[test]
     fn test_simple_multi() {
         let p = build_multi_portfolio();
-        assert_that(&p.total_value()).is_close_to(10_000.0, 1.0);
+        assert_that(&p.total_value()).is_equal_to(dec!(10_000));
 
         let r = run_balancing(p);
 
-        assert_that(&r.total_cash).is_close_to(0.0, 0.1);
-        check_shares(&r, "taxed", "A", 400.0); // 400*$10 = $4k, 50%
-        check_shares(&r, "ira", "A", 100.0);
+        assert_that(&r.total_cash).is_equal_to(dec!(0));
+        check_shares(&r, "taxed", "A", dec!(400)); // 400*$10 = $4k, 50%
+        check_shares(&r, "ira", "A", dec!(100));
 
-        check_shares(&r, "taxed", "B", 40.0); // 40*$100 = $4k, 50%
-        check_shares(&r, "ira", "B", 10.0);
+        check_shares(&r, "taxed", "B", dec!(40)); // 40*$100 = $4k, 50%
+        check_shares(&r, "ira", "B", dec!(10));
 
         check_allocation(&r, "A", 0.5);
         check_allocation(&r, "B", 0.5);
@@ -523,24 +1012,24 @@ mod multiple_accounts {
         let mut p = build_multi_portfolio();
         {
             let taxed = p.accounts.index_mut(0);
-            taxed.cash = 0.0;
-            taxed.positions.insert(String::from("A"), 500.0);
+            taxed.cash = dec!(0);
+            taxed.positions.insert(String::from("A"), dec!(500).into());
         }
         {
             let ira = p.accounts.index_mut(1);
-            ira.cash = 3_000.0;
-            ira.positions.insert(String::from("A"), 200.0);
+            ira.cash = dec!(3_000);
+            ira.positions.insert(String::from("A"), dec!(200).into());
         }
 
         let r = run_balancing(p);
 
-        assert_that(&r.total_cash).is_close_to(0.0, 0.1);
+        assert_that(&r.total_cash).is_equal_to(dec!(0));
         // we have the needed 50% of A in the taxed account, sell extra from the ira
-        check_shares(&r, "taxed", "A", 500.0);
-        check_shares(&r, "ira", "A", 0.0);
+        check_shares(&r, "taxed", "A", dec!(500));
+        check_shares(&r, "ira", "A", dec!(0));
 
-        check_shares(&r, "taxed", "B", 0.0);
-        check_shares(&r, "ira", "B", 50.0);
+        check_shares(&r, "taxed", "B", dec!(0));
+        check_shares(&r, "ira", "B", dec!(50));
     }
 
     #[test]
@@ -551,14 +1040,360 @@ mod multiple_accounts {
 
         let r = run_balancing(p);
 
-        assert_that(&r.total_cash).is_close_to(0.0, 0.1);
-        check_shares(&r, "taxed", "A", 300.0);
-        check_shares(&r, "ira", "A", 200.0);
+        assert_that(&r.total_cash).is_equal_to(dec!(0));
+        check_shares(&r, "taxed", "A", dec!(300));
+        check_shares(&r, "ira", "A", dec!(200));
 
-        check_shares(&r, "taxed", "B", 50.0);
-        check_shares(&r, "ira", "B", 0.0); // IRA ends up entirely holding high-yield
+        check_shares(&r, "taxed", "B", dec!(50));
+        check_shares(&r, "ira", "B", dec!(0)); // IRA ends up entirely holding high-yield
 
         check_allocation(&r, "A", 0.5);
         check_allocation(&r, "B", 0.5);
     }
+
+    fn build_typed_portfolio() -> Portfolio {
+        let mut p = Portfolio::new();
+        p.accounts.push(Account::new("taxed"));
+        let mut roth = Account::new("roth");
+        roth.account_type = Some(AccountType::Roth);
+        p.accounts.push(roth);
+        let mut ira = Account::new("ira");
+        ira.account_type = Some(AccountType::Traditional);
+        p.accounts.push(ira);
+        p.market.push(Investment::new("A", dec!(10)));
+        p.market.push(Investment::new("B", dec!(100)));
+        p.market.index_mut(0).div_yield = Some(0.04); // A
+        p.market.index_mut(1).div_yield = Some(0.01); // B
+        p
+    }
+
+    #[test]
+    fn place_by_account_type() {
+        let mut p = build_typed_portfolio();
+        p.accounts.index_mut(0).cash = dec!(6_000);
+        p.accounts.index_mut(1).cash = dec!(2_000);
+        p.accounts.index_mut(2).cash = dec!(2_000);
+        p.target.insert(String::from("A"), 0.3.into());
+        p.target.insert(String::from("B"), 0.7.into());
+
+        let r = run_balancing(p);
+
+        // high-yield A fills the traditional IRA before the Roth, B goes in taxable then Roth
+        assert_that(&r.total_cash).is_equal_to(dec!(0));
+        check_shares(&r, "ira", "A", dec!(200));
+        check_shares(&r, "roth", "A", dec!(100));
+        check_shares(&r, "taxed", "B", dec!(60));
+        check_shares(&r, "roth", "B", dec!(10));
+    }
+
+    #[test]
+    fn sell_by_account_type() {
+        let mut p = build_typed_portfolio();
+        p.accounts.swap(1, 2); // the IRA comes first, but A belongs there
+        p.accounts
+            .index_mut(0)
+            .positions
+            .insert(String::from("B"), dec!(40).into());
+        p.accounts
+            .index_mut(1)
+            .positions
+            .insert(String::from("A"), dec!(200).into());
+        p.accounts
+            .index_mut(2)
+            .positions
+            .insert(String::from("A"), dec!(200).into());
+        p.target.insert(String::from("A"), 0.25.into());
+        p.target.insert(String::from("B"), 0.75.into());
+
+        let r = run_balancing(p);
+
+        assert_that(&r.total_cash).is_equal_to(dec!(0));
+        check_shares(&r, "ira", "A", dec!(200));
+        check_shares(&r, "roth", "A", dec!(0));
+        check_shares(&r, "roth", "B", dec!(20));
+        check_shares(&r, "taxed", "B", dec!(40));
+    }
+
+    #[test]
+    fn place_by_tax_drag() {
+        let mut p = build_typed_portfolio();
+        p.market.clear();
+        p.tax_rates = Some(TaxRates::new(0.32, 0.15));
+        p.accounts.index_mut(0).cash = dec!(6_000);
+        p.accounts.index_mut(1).cash = dec!(1_000);
+        p.accounts.index_mut(2).cash = dec!(3_000);
+        for (symbol, target) in [("BND", 0.3), ("VTI", 0.4), ("VXUS", 0.3)] {
+            p.target.insert(String::from(symbol), target.into());
+            p.market.push(Investment::new(symbol, dec!(100)));
+        }
+        p.market.index_mut(0).div_yield = Some(0.04);
+        let vti = p.market.index_mut(1);
+        vti.div_yield = Some(0.015);
+        vti.qualified_ratio = Some(1.0);
+        vti.expected_growth = Some(0.07);
+        let vxus = p.market.index_mut(2);
+        vxus.div_yield = Some(0.03);
+        vxus.qualified_ratio = Some(0.7);
+        vxus.foreign_tax = Some(0.004);
+
+        let r = run_balancing(p);
+
+        // bonds in the IRA, growth in the Roth, and international in taxable for the credit
+        assert_that(&r.total_cash).is_equal_to(dec!(0));
+        check_shares(&r, "ira", "BND", dec!(30));
+        check_shares(&r, "roth", "VTI", dec!(10));
+        check_shares(&r, "taxed", "VTI", dec!(30));
+        check_shares(&r, "taxed", "VXUS", dec!(30));
+    }
+
+    #[test]
+    fn keep_cash_reserves() {
+        let mut p = build_multi_portfolio();
+        p.accounts.index_mut(0).reserve = Some(CashReserve::Percent { percent: 10.0 });
+        p.accounts.index_mut(1).reserve = Some(CashReserve::Dollars(dec!(1_000)));
+
+        let r = run_balancing(p);
+
+        // $800 + $1,000 is held back, the remaining $8,200 is split evenly
+        assert_that(&r.total_cash).is_equal_to(dec!(0));
+        assert_that(&r.total_reserved).is_equal_to(dec!(1_800));
+        assert_that(&r.reserved["taxed"]).is_equal_to(dec!(800));
+        assert_that(&r.reserved["ira"]).is_equal_to(dec!(1_000));
+        assert_that(&r.cash["ira"]).is_equal_to(dec!(0));
+        check_shares(&r, "taxed", "A", dec!(360));
+        check_shares(&r, "taxed", "B", dec!(36));
+        check_shares(&r, "ira", "A", dec!(50));
+        check_shares(&r, "ira", "B", dec!(5));
+        check_allocation(&r, "A", 0.41);
+        check_allocation(&r, "cash", 0.18);
+    }
+
+    fn build_menu_portfolio() -> Portfolio {
+        let mut p = build_multi_portfolio();
+        p.market.index_mut(0).div_yield = Some(0.04); // A
+        p.market.index_mut(1).div_yield = Some(0.01); // B
+
+        // the 401k can only hold B
+        p.accounts.index_mut(1).name = String::from("401k");
+        p.accounts.index_mut(1).allowed = Some(vec![String::from("B")].into_iter().collect());
+        p
+    }
+
+    #[test]
+    fn buy_from_account_menus() {
+        let r = run_balancing(build_menu_portfolio());
+
+        // high-yield A can't go in the 401k, so the taxable account holds all of it
+        assert_that(&r.total_cash).is_equal_to(dec!(0));
+        check_shares(&r, "401k", "A", dec!(0));
+        check_shares(&r, "401k", "B", dec!(20));
+        check_shares(&r, "taxed", "A", dec!(500));
+        check_shares(&r, "taxed", "B", dec!(30));
+    }
+
+    #[test]
+    fn sell_outside_account_menu() {
+        let mut p = build_menu_portfolio();
+        p.accounts.index_mut(0).cash = dec!(4_000);
+        {
+            let k = p.accounts.index_mut(1);
+            k.cash = dec!(0);
+            k.positions.insert(String::from("A"), dec!(600).into());
+        }
+
+        let r = run_balancing(p);
+
+        // overweight A can be sold, but the proceeds only buy B
+        assert_that(&r.total_cash).is_equal_to(dec!(0));
+        check_shares(&r, "401k", "A", dec!(500));
+        check_shares(&r, "401k", "B", dec!(10));
+        check_shares(&r, "taxed", "A", dec!(0));
+        check_shares(&r, "taxed", "B", dec!(40));
+    }
+
+    fn build_class_portfolio() -> Portfolio {
+        let mut p = build_multi_portfolio();
+        p.target.clear();
+        p.target.insert(String::from("sp500"), 0.5.into());
+        p.target.insert(String::from("B"), 0.5.into());
+        p.classes.insert(
+            String::from("sp500"),
+            vec![String::from("VOO"), String::from("FXAIX")],
+        );
+        p.market.push(Investment::new("VOO", dec!(10)));
+        p.market.push(Investment::new("FXAIX", dec!(20)));
+        p
+    }
+
+    #[test]
+    fn buy_asset_classes() {
+        let mut p = build_class_portfolio();
+        // the 401k doesn't offer VOO
+        p.accounts.index_mut(1).name = String::from("401k");
+        p.accounts.index_mut(1).allowed = Some(vec![String::from("FXAIX")].into_iter().collect());
+
+        let r = run_balancing(p);
+
+        assert_that(&r.total_cash).is_equal_to(dec!(0));
+        check_shares(&r, "401k", "FXAIX", dec!(100));
+        check_shares(&r, "taxed", "VOO", dec!(300));
+        check_shares(&r, "taxed", "B", dec!(50));
+        check_allocation(&r, "sp500", 0.5);
+        check_allocation(&r, "B", 0.5);
+        assert_that(&r.allocations.get("VOO")).is_none();
+        assert_that(&r.drift["sp500"]).is_close_to(0.0, 0.001);
+    }
+
+    #[test]
+    fn sell_asset_classes() {
+        let mut p = build_class_portfolio();
+        p.accounts.index_mut(0).cash = dec!(0);
+        p.accounts
+            .index_mut(0)
+            .positions
+            .insert(String::from("VOO"), dec!(600).into());
+        p.accounts
+            .index_mut(1)
+            .positions
+            .insert(String::from("FXAIX"), dec!(100).into());
+
+        let r = run_balancing(p);
+
+        // $8k of the class is held between two funds, $3k is sold from the ira first
+        assert_that(&r.total_cash).is_equal_to(dec!(0));
+        check_shares(&r, "ira", "FXAIX", dec!(0));
+        check_shares(&r, "taxed", "VOO", dec!(500));
+        check_shares(&r, "ira", "B", dec!(40));
+        check_shares(&r, "taxed", "B", dec!(10));
+        assert_that(&r.drift["B"]).is_close_to(0.0, 0.001);
+    }
+
+    fn build_tree_portfolio() -> Portfolio {
+        let mut p = build_multi_portfolio();
+        p.target = serde_json::from_str(
+            r#"{"equity": {"weight": 0.8, "children": {"A": 0.5, "C": 0.5}}, "B": 0.2}"#,
+        )
+        .unwrap();
+        p.market.push(Investment::new("C", dec!(10)));
+        p
+    }
+
+    #[test]
+    fn balance_target_tree() {
+        let r = run_balancing(build_tree_portfolio());
+
+        assert_that(&r.total_cash).is_equal_to(dec!(0));
+        check_allocation(&r, "A", 0.4);
+        check_allocation(&r, "C", 0.4);
+        check_allocation(&r, "B", 0.2);
+        for node in ["equity", "A", "B", "C"].iter() {
+            assert_that(&r.drift[*node]).is_close_to(0.0, 0.001);
+        }
+    }
+
+    #[test]
+    fn drift_at_every_level() {
+        let mut p = build_tree_portfolio();
+        p.accounts.index_mut(0).cash = dec!(0);
+        p.accounts
+            .index_mut(0)
+            .positions
+            .insert(String::from("A"), dec!(600).into());
+        p.accounts.index_mut(1).cash = dec!(0);
+        p.accounts
+            .index_mut(1)
+            .positions
+            .insert(String::from("B"), dec!(20).into());
+        p.no_sale_accounts.insert(String::from("taxed"));
+        p.no_sale_accounts.insert(String::from("ira"));
+
+        let r = run_balancing(p);
+
+        // equity is only a little under target, but it's all in A
+        assert_that(&r.trades).is_empty();
+        assert_that(&r.drift["equity"]).is_close_to(-0.05, 0.001);
+        assert_that(&r.drift["A"]).is_close_to(0.35, 0.001);
+        assert_that(&r.drift["C"]).is_close_to(-0.4, 0.001);
+        assert_that(&r.drift["B"]).is_close_to(0.05, 0.001);
+    }
+
+    #[test]
+    fn only_trade_out_of_band() {
+        let mut p = build_multi_portfolio();
+        p.target.clear();
+        p.target.insert(String::from("A"), 0.6.into());
+        p.target.insert(String::from("B"), 0.3.into());
+        p.target.insert(String::from("C"), 0.1.into());
+        p.market.push(Investment::new("C", dec!(10)));
+        p.tolerance = Some(tolerance::Band::new(Some(5.0), Some(25.0)));
+        {
+            let taxed = p.accounts.index_mut(0);
+            taxed.cash = dec!(400);
+            taxed.positions.insert(String::from("A"), dec!(620).into());
+            taxed.positions.insert(String::from("B"), dec!(28).into());
+            taxed.positions.insert(String::from("C"), dec!(60).into());
+        }
+        p.accounts.index_mut(1).cash = dec!(0);
+
+        let r = run_balancing(p);
+
+        // A and B are 2 points off, but C is 4 points under a 2.5 point band
+        assert_that(&r.out_of_band).has_length(1);
+        let json = serde_json::to_value(&r.out_of_band[0]).unwrap();
+        assert_that(&json["symbol"]).is_equal_to(serde_json::json!("C"));
+        assert_that(&json["reason"]).is_equal_to(serde_json::json!("relative"));
+        assert_that(&r.trades).has_length(1);
+        check_shares(&r, "taxed", "A", dec!(620));
+        check_shares(&r, "taxed", "B", dec!(28));
+        check_shares(&r, "taxed", "C", dec!(100));
+    }
+
+    #[test]
+    fn invest_cash_inside_bands() {
+        let mut p = Portfolio::new();
+        let mut acct = Account::new("taxed");
+        acct.positions.insert(String::from("A"), dec!(50).into());
+        acct.positions.insert(String::from("B"), dec!(500).into());
+        p.accounts.push(acct);
+        p.target.insert(String::from("A"), 0.5.into());
+        p.target.insert(String::from("B"), 0.5.into());
+        p.market.push(Investment::new("A", dec!(100)));
+        p.market.push(Investment::new("B", dec!(10)));
+        p.tolerance = Some(tolerance::Band::new(Some(5.0), None));
+        let deposits = vec![(String::from("taxed"), dec!(1_000))];
+        p.deposits = Some(deposits.into_iter().collect());
+
+        let r = run_balancing(p);
+
+        // nothing drifted out of its band, but the deposit still gets invested
+        assert_that(&r.out_of_band).is_empty();
+        assert_that(&r.total_cash).is_equal_to(dec!(0));
+        check_shares(&r, "taxed", "A", dec!(55));
+        check_shares(&r, "taxed", "B", dec!(550));
+    }
+
+    #[test]
+    fn invest_deposits_only() {
+        let mut p = build_multi_portfolio();
+        p.accounts.index_mut(0).cash = dec!(0);
+        p.accounts
+            .index_mut(0)
+            .positions
+            .insert(String::from("A"), dec!(700).into());
+        p.accounts.index_mut(1).cash = dec!(0);
+        let deposits =
+            c! { String::from(a) => d, for (a, d) in [("taxed", dec!(500)), ("ira", dec!(1_500))] };
+        p.deposits = Some(deposits);
+
+        let r = run_balancing(p);
+
+        // A is overweight, but only the new money is used to catch up
+        assert_that(&r.total_cash).is_equal_to(dec!(0));
+        assert_that(&r.trades.iter().all(|t| t.action == Action::Buy)).is_true();
+        check_shares(&r, "taxed", "A", dec!(700));
+        check_shares(&r, "taxed", "B", dec!(5));
+        check_shares(&r, "ira", "B", dec!(15));
+        assert_that(&r.drift["A"]).is_close_to(7.0 / 9.0 - 0.5, 0.001);
+        assert_that(&r.drift["B"]).is_close_to(2.0 / 9.0 - 0.5, 0.001);
+    }
 }
@@ -0,0 +1,75 @@
+use super::money::Decimal;
+
+/// What the broker charges for each order, e.g. `{"per_trade": 4.95}` or
+/// `{"per_share": 0.005, "minimum": 1.0}`
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Commission {
+    #[serde(default)]
+    per_trade: Decimal,
+    #[serde(default)]
+    per_share: Decimal,
+    minimum: Option<Decimal>, // per order
+    maximum: Option<Decimal>, // per order
+}
+
+impl Commission {
+    pub fn new(per_trade: Decimal, per_share: Decimal) -> Self {
+        Commission {
+            per_trade,
+            per_share,
+            minimum: None,
+            maximum: None,
+        }
+    }
+
+    pub fn is_valid(&self) -> bool {
+        [
+            Some(self.per_trade),
+            Some(self.per_share),
+            self.minimum,
+            self.maximum,
+        ]
+        .iter()
+        .flatten()
+        .all(|c| !c.is_sign_negative())
+    }
+
+    /// Cost of a single order for `shares`, bought or sold
+    pub fn cost(&self, shares: Decimal) -> Decimal {
+        if shares <= Decimal::ZERO {
+            return Decimal::ZERO;
+        }
+        let cost = self.per_trade + self.per_share * shares;
+        let cost = self.minimum.map_or(cost, |m| cost.max(m));
+        self.maximum.map_or(cost, |m| cost.min(m))
+    }
+
+    /// Fixed and per-share parts of the cost, for models that need it to be linear. The
+    /// minimum counts as a fixed cost, and the maximum is left out.
+    pub fn linear(&self) -> (Decimal, Decimal) {
+        let fixed = self.per_trade.max(self.minimum.unwrap_or_default());
+        (fixed, self.per_share)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::accounts::money::dec;
+    use spectral::prelude::*;
+
+    #[test]
+    fn commission_cost() {
+        let flat = Commission::new(dec!(4.95), Decimal::ZERO);
+        assert_that(&flat.cost(dec!(10))).is_equal_to(dec!(4.95));
+        assert_that(&flat.cost(Decimal::ZERO)).is_equal_to(Decimal::ZERO);
+
+        let c: Commission =
+            serde_json::from_str(r#"{"per_share": 0.005, "minimum": 1.0, "maximum": 5.0}"#)
+                .unwrap();
+        assert_that(&c.cost(dec!(100))).is_equal_to(dec!(1));
+        assert_that(&c.cost(dec!(500))).is_equal_to(dec!(2.5));
+        assert_that(&c.cost(dec!(5_000))).is_equal_to(dec!(5));
+        assert_that(&Commission::new(dec!(-1), Decimal::ZERO).is_valid()).is_false();
+    }
+}
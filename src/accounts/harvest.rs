@@ -0,0 +1,311 @@
+use super::*;
+
+/// Buying a substantially identical fund this many days before or after selling at a loss
+/// disallows the loss
+const WASH_SALE_DAYS: i64 = 30;
+
+/// A purchase that isn't visible in the lots, e.g. a dividend reinvested in an IRA
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RecentBuy {
+    account: String,
+    symbol: String,
+    date: Date,
+}
+
+impl RecentBuy {
+    pub fn new(account: &str, symbol: &str, date: Date) -> Self {
+        RecentBuy {
+            account: account.to_owned(),
+            symbol: symbol.to_owned(),
+            date,
+        }
+    }
+}
+
+/// A proposed swap out of a fund held at a loss and into a substitute with the same exposure.
+/// These aren't applied to the balanced positions or included in the trades.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Harvest {
+    account: String,
+    sell: String,
+    shares_sold: Decimal,
+    buy: String,
+    shares_bought: Decimal,
+    loss: Decimal,
+    lots: Vec<LotSale>,
+}
+
+impl Harvest {
+    /// Rounds to the cent, and to the reported share precision
+    pub fn round(&mut self) {
+        self.shares_sold = money::shares(self.shares_sold);
+        self.shares_bought = money::shares(self.shares_bought);
+        self.loss = money::cents(self.loss);
+        self.lots.iter_mut().for_each(LotSale::round);
+    }
+}
+
+/// Looks for losses to harvest in the taxable accounts once balancing is done
+pub fn find_harvests(
+    portfolio: &Portfolio,
+    results: &Results,
+    prices: &HashMap<&String, Decimal>,
+) -> Vec<Harvest> {
+    let date = results.as_of.unwrap_or_else(Date::today);
+    let min_loss = portfolio.harvest_min_loss.unwrap_or_default();
+    let mut harvests = vec![];
+
+    for account in portfolio.accounts.iter().filter(|a| !a.is_sheltered()) {
+        let held = match results.lots.get(&account.name) {
+            Some(held) => held,
+            None => continue,
+        };
+        let mut symbols: Vec<&String> = held.keys().collect();
+        symbols.sort();
+        for symbol in symbols {
+            // the swap sells at the bid and buys the substitute at its ask
+            let price = match prices.get(symbol) {
+                Some(price) => results.fill_price(symbol, *price, -Decimal::ONE),
+                None => continue,
+            };
+            let losing: Vec<Lot> = held[symbol]
+                .iter()
+                .filter(|l| l.gain(price) < Decimal::ZERO)
+                .cloned()
+                .collect();
+            let shares: Decimal = losing.iter().map(|l| l.shares()).sum();
+            let loss: Decimal = losing.iter().map(|l| -l.shares() * l.gain(price)).sum();
+            if shares <= Decimal::ZERO || loss <= min_loss {
+                continue;
+            }
+            let conflict =
+                wash_sale_conflict(portfolio, results, &account.name, symbol, price, date);
+            if let Some(conflict) = conflict {
+                println!(
+                    "not harvesting {} in acct={}, would wash with {}",
+                    symbol, account.name, conflict
+                );
+                continue;
+            }
+            // a substitute has to count towards the same target, or the next run would see the
+            // harvested target as underweight and buy it back inside the wash sale window
+            let class = portfolio.class_of(symbol);
+            let substitute = portfolio
+                .substitutes_for(symbol)
+                .into_iter()
+                .filter(|s| account.allows(s) && portfolio.class_of(s) == class)
+                .find_map(|s| {
+                    let ask = |p: &Decimal| results.fill_price(s, *p, Decimal::ONE);
+                    prices.get(s).map(|p| (s, ask(p)))
+                });
+            let (substitute, sub_price) = match substitute {
+                Some(s) => s,
+                None => continue,
+            };
+            let sales = lots::sell(
+                &mut losing.clone(),
+                shares,
+                price,
+                date,
+                LotSelection::LossFirst,
+            );
+            harvests.push(Harvest {
+                account: account.name.clone(),
+                sell: symbol.clone(),
+                shares_sold: shares,
+                buy: substitute.clone(),
+                shares_bought: account.tradeable(shares * price / sub_price),
+                loss,
+                lots: sales,
+            });
+        }
+    }
+    harvests
+}
+
+/// Finds a purchase of a fund substantially identical to `symbol`, in any account, close
+/// enough to today to disallow a loss. The losing lots we'd be selling don't count.
+fn wash_sale_conflict(
+    portfolio: &Portfolio,
+    results: &Results,
+    seller: &str,
+    symbol: &str,
+    price: Decimal,
+    date: Date,
+) -> Option<String> {
+    let identical = portfolio.identical_to(symbol);
+    let recent = |d: Date| d.days_until(date).abs() <= WASH_SALE_DAYS;
+
+    for (account, held) in results.lots.iter() {
+        for (sym, lots) in held.iter().filter(|(s, _)| identical.contains(s.as_str())) {
+            let harvested =
+                |l: &Lot| account == seller && sym == symbol && l.gain(price) < Decimal::ZERO;
+            if lots.iter().any(|l| recent(l.acquired()) && !harvested(l)) {
+                return Some(format!("{} bought in {}", sym, account));
+            }
+        }
+    }
+    let bought = results
+        .trades
+        .iter()
+        .find(|t| t.action == Action::Buy && identical.contains(t.symbol.as_str()));
+    if let Some(t) = bought {
+        return Some(format!("{} being bought in {}", t.symbol, t.account));
+    }
+    portfolio
+        .recent_buys
+        .iter()
+        .find(|b| identical.contains(b.symbol.as_str()) && recent(b.date))
+        .map(|b| format!("{} bought in {}", b.symbol, b.account))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::run_balancing;
+    use spectral::prelude::*;
+
+    fn build_harvest_portfolio() -> Portfolio {
+        let mut p = Portfolio::new();
+        p.as_of = Some(Date::new(2020, 6, 1));
+        p.target.insert(String::from("total"), 1.0.into());
+        p.classes.insert(
+            String::from("total"),
+            vec![String::from("VTI"), String::from("ITOT")],
+        );
+        p.market.push(Investment::new("VTI", dec!(100)));
+        p.market.push(Investment::new("ITOT", dec!(50)));
+        p.substitutes
+            .push(vec![String::from("VTI"), String::from("ITOT")]);
+        let mut taxed = Account::new("taxed");
+        let lots = vec![
+            Lot::new(Date::new(2019, 1, 1), dec!(10), dec!(120)),
+            Lot::new(Date::new(2019, 2, 1), dec!(5), dec!(80)),
+        ];
+        taxed
+            .positions
+            .insert(String::from("VTI"), Position::Lots(lots));
+        p.accounts.push(taxed);
+        let mut ira = Account::new("ira");
+        ira.tax_sheltered = true;
+        p.accounts.push(ira);
+        p
+    }
+
+    #[test]
+    fn harvest_losing_lots() {
+        let p = build_harvest_portfolio();
+
+        let r = run_balancing(p);
+
+        assert_that(&r.trades).is_empty();
+        assert_that(&r.harvests).has_length(1);
+        let h = &r.harvests[0];
+        assert_that(&h.sell).is_equal_to(String::from("VTI"));
+        assert_that(&h.buy).is_equal_to(String::from("ITOT"));
+        assert_that(&h.shares_sold).is_equal_to(dec!(10));
+        assert_that(&h.shares_bought).is_equal_to(dec!(20));
+        assert_that(&h.loss).is_equal_to(dec!(200));
+        assert_that(&h.lots).has_length(1);
+    }
+
+    #[test]
+    fn harvest_at_the_bid_and_ask() {
+        let mut p = build_harvest_portfolio();
+        p.market[0].bid = Some(dec!(99));
+        p.market[1].ask = Some(dec!(51));
+
+        let r = run_balancing(p);
+
+        // $990 from the sale only buys 19 shares of ITOT at its ask
+        let h = &r.harvests[0];
+        assert_that(&h.loss).is_equal_to(dec!(210));
+        assert_that(&h.shares_bought).is_equal_to(dec!(19));
+    }
+
+    #[test]
+    fn no_harvest_below_minimum_loss() {
+        let mut p = build_harvest_portfolio();
+        p.harvest_min_loss = Some(dec!(250));
+
+        let r = run_balancing(p);
+
+        assert_that(&r.harvests).is_empty();
+    }
+
+    #[test]
+    fn no_harvest_into_unavailable_fund() {
+        let mut p = build_harvest_portfolio();
+        p.accounts[0].allowed = Some(vec![String::from("VTI")].into_iter().collect());
+
+        let r = run_balancing(p);
+
+        assert_that(&r.harvests).is_empty();
+    }
+
+    #[test]
+    fn no_harvest_into_other_class() {
+        let mut p = build_harvest_portfolio();
+        p.classes
+            .insert(String::from("total"), vec![String::from("VTI")]);
+        p.target.insert(String::from("ITOT"), 0.0.into());
+
+        let r = run_balancing(p);
+
+        assert_that(&r.harvests).is_empty();
+    }
+
+    #[test]
+    fn hold_substitute_after_harvest() {
+        // the portfolio once the suggested swap of 10 VTI for 20 ITOT is made
+        let mut p = build_harvest_portfolio();
+        let lots = vec![Lot::new(Date::new(2019, 2, 1), dec!(5), dec!(80))];
+        p.accounts[0]
+            .positions
+            .insert(String::from("VTI"), Position::Lots(lots));
+        let lots = vec![Lot::new(Date::new(2020, 6, 1), dec!(20), dec!(50))];
+        p.accounts[0]
+            .positions
+            .insert(String::from("ITOT"), Position::Lots(lots));
+        p.as_of = Some(Date::new(2020, 6, 10));
+
+        let r = run_balancing(p);
+
+        // ITOT still counts towards the target, so it isn't sold and VTI isn't bought back
+        assert_that(&r.trades).is_empty();
+        assert_that(&r.drift["total"]).is_close_to(0.0, 0.001);
+        assert_that(&r.harvests).is_empty();
+    }
+
+    #[test]
+    fn no_harvest_after_sheltered_buy() {
+        let mut p = build_harvest_portfolio();
+        let lots = vec![Lot::new(Date::new(2020, 5, 15), dec!(1), dec!(100))];
+        p.accounts[1]
+            .positions
+            .insert(String::from("VTI"), Position::Lots(lots));
+
+        let r = run_balancing(p);
+
+        assert_that(&r.harvests).is_empty();
+    }
+
+    #[test]
+    fn no_harvest_after_identical_buy() {
+        let mut p = build_harvest_portfolio();
+        p.identical
+            .push(vec![String::from("VTI"), String::from("VTSAX")]);
+        let bought = Date::new(2020, 5, 20);
+        p.recent_buys.push(RecentBuy::new("ira", "VTSAX", bought));
+
+        let r = run_balancing(p);
+        assert_that(&r.harvests).is_empty();
+
+        let mut p = build_harvest_portfolio();
+        let long_ago = Date::new(2020, 4, 1);
+        p.recent_buys.push(RecentBuy::new("ira", "VTI", long_ago));
+
+        let r = run_balancing(p);
+        assert_that(&r.harvests).has_length(1);
+    }
+}
@@ -0,0 +1,179 @@
+use super::*;
+use stats::median;
+use std::cmp::Ordering;
+
+/// The investor's marginal tax rates, as fractions, e.g. 0.24 for 24%
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TaxRates {
+    ordinary: f32,  // interest and non-qualified dividends
+    qualified: f32, // qualified dividends
+}
+
+impl TaxRates {
+    pub fn new(ordinary: f32, qualified: f32) -> Self {
+        TaxRates {
+            ordinary,
+            qualified,
+        }
+    }
+
+    pub fn qualified(&self) -> f32 {
+        self.qualified
+    }
+
+    pub fn is_valid(&self) -> bool {
+        [self.ordinary, self.qualified]
+            .iter()
+            .all(|r| (0.0..=1.0).contains(r))
+    }
+}
+
+/// What a fund mostly returns, which decides the accounts it's best held in
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Placement {
+    Income,    // costs the most in taxes each year, e.g. bonds
+    Growth,    // expected to grow the most, best held where the growth is never taxed
+    Efficient, // cheap to hold in taxable, e.g. international funds with a foreign tax credit
+}
+
+impl Investment {
+    /// Taxes owed each year from holding the fund in a taxable account, as a fraction of its
+    /// value. Without tax rates, every dividend is assumed to be taxed the same.
+    pub fn tax_drag(&self, rates: Option<&TaxRates>) -> f32 {
+        let div_yield = self.div_yield.unwrap_or(0.0);
+        let tax = match rates {
+            _ if self.municipal.unwrap_or(false) => 0.0,
+            Some(rates) => {
+                let qualified = self.qualified_ratio.unwrap_or(0.0);
+                div_yield * (qualified * rates.qualified + (1.0 - qualified) * rates.ordinary)
+            }
+            None => div_yield,
+        };
+        // foreign taxes can only be credited back in a taxable account
+        tax - self.foreign_tax.unwrap_or(0.0)
+    }
+}
+
+/// Where each target is best held. Targets with more tax drag than most are sheltered, from the
+/// most drag down until the sheltered accounts are full. Of the rest, the faster growing
+/// targets go to Roth-like accounts and the others stay taxable.
+pub fn placements(portfolio: &Portfolio) -> HashMap<String, Placement> {
+    let rates = portfolio.tax_rates.as_ref();
+    // a class without funds has no yield or growth to place by
+    let fund = |class: &str| {
+        let symbol = *portfolio.members(class).first()?;
+        portfolio.market.iter().find(|i| i.symbol == symbol)
+    };
+    let growth = |class: &str| fund(class).and_then(|i| i.expected_growth).unwrap_or(0.0);
+
+    let targets = portfolio.targets();
+    let total = portfolio.investable_value();
+    let mut sheltered: Decimal = portfolio
+        .accounts
+        .iter()
+        .filter(|a| a.is_sheltered())
+        .map(|a| a.value(&portfolio.market) - a.reserve(&portfolio.market))
+        .sum();
+    let drags = c! { c => fund(c).map_or(0.0, |i| i.tax_drag(rates)), for c in targets.keys() };
+    let median_drag = median(drags.values().cloned());
+    let median_growth = median(targets.keys().map(|c| growth(c)));
+    let above = |value: f32, median: Option<f64>| median.is_some_and(|m| value as f64 > m);
+
+    let mut ranked: Vec<(&String, f32, f32)> = targets
+        .iter()
+        .map(|(class, weight)| (class, *weight, drags[class]))
+        .collect();
+    // most tax drag first
+    ranked.sort_by(|(a, _, a_drag), (b, _, b_drag)| {
+        b_drag
+            .partial_cmp(a_drag)
+            .unwrap_or(Ordering::Equal)
+            .then_with(|| a.cmp(b))
+    });
+
+    let mut placements = HashMap::new();
+    for (class, weight, drag) in ranked {
+        let placement = if above(drag, median_drag) && sheltered > Decimal::ZERO {
+            sheltered -= money::decimal(weight) * total;
+            Placement::Income
+        } else if above(growth(class), median_growth) {
+            Placement::Growth
+        } else {
+            Placement::Efficient
+        };
+        placements.insert(class.clone(), placement);
+    }
+    placements
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use spectral::prelude::*;
+
+    fn fund(symbol: &str, div_yield: f32, qualified: f32, growth: f32) -> Investment {
+        let mut i = Investment::new(symbol, dec!(100));
+        i.div_yield = Some(div_yield);
+        i.qualified_ratio = Some(qualified);
+        i.expected_growth = Some(growth);
+        i
+    }
+
+    #[test]
+    fn fund_tax_drag() {
+        let rates = TaxRates::new(0.32, 0.15);
+        let bonds = fund("BND", 0.04, 0.0, 0.0);
+        assert_that(&bonds.tax_drag(Some(&rates))).is_close_to(0.0128, 0.00001);
+        assert_that(&bonds.tax_drag(None)).is_close_to(0.04, 0.00001);
+
+        let mut intl = fund("VXUS", 0.03, 0.7, 0.05);
+        assert_that(&intl.tax_drag(Some(&rates))).is_close_to(0.00603, 0.00001);
+        intl.foreign_tax = Some(0.004);
+        assert_that(&intl.tax_drag(Some(&rates))).is_close_to(0.00203, 0.00001);
+
+        let mut muni = fund("VTEB", 0.03, 0.0, 0.0);
+        muni.municipal = Some(true);
+        assert_that(&muni.tax_drag(Some(&rates))).is_close_to(0.0, 0.00001);
+        assert_that(&TaxRates::new(1.2, 0.15).is_valid()).is_false();
+    }
+
+    #[test]
+    fn rank_by_tax_drag() {
+        let mut p = Portfolio::new();
+        p.tax_rates = Some(TaxRates::new(0.32, 0.15));
+        p.market.push(fund("BND", 0.04, 0.0, 0.02));
+        p.market.push(fund("VTI", 0.015, 1.0, 0.07));
+        p.market.push(fund("VXUS", 0.03, 0.7, 0.02));
+        p.market.push(fund("VTEB", 0.03, 0.0, 0.02));
+        p.market[3].municipal = Some(true);
+        p.target.insert(String::from("BND"), 0.4.into());
+        p.target.insert(String::from("VTI"), 0.3.into());
+        p.target.insert(String::from("VXUS"), 0.2.into());
+        p.target.insert(String::from("VTEB"), 0.1.into());
+        let mut ira = Account::new("ira");
+        ira.tax_sheltered = true;
+        ira.cash = dec!(6_000);
+        p.accounts.push(ira);
+        let mut taxed = Account::new("taxed");
+        taxed.cash = dec!(4_000);
+        p.accounts.push(taxed);
+
+        // without a credit, international has more drag than US stocks and gets sheltered
+        let placements = placements(&p);
+        assert_that(&placements["BND"]).is_equal_to(Placement::Income);
+        assert_that(&placements["VXUS"]).is_equal_to(Placement::Income);
+        assert_that(&placements["VTI"]).is_equal_to(Placement::Growth);
+        assert_that(&placements["VTEB"]).is_equal_to(Placement::Efficient);
+
+        // with it, international is cheaper to hold in taxable than US stocks
+        p.market[2].foreign_tax = Some(0.004);
+        let placements = super::placements(&p);
+        assert_that(&placements["VTI"]).is_equal_to(Placement::Income);
+        assert_that(&placements["VXUS"]).is_equal_to(Placement::Efficient);
+
+        // a class with no funds has nothing to drag, and is placed without a panic
+        p.classes.insert(String::from("VTEB"), vec![]);
+        let placements = super::placements(&p);
+        assert_that(&placements["VTEB"]).is_equal_to(Placement::Efficient);
+    }
+}
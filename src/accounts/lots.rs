@@ -0,0 +1,496 @@
+use super::money::{self, dec, Decimal};
+use serde::de::{self, Deserialize, Deserializer};
+use serde::ser::{Serialize, Serializer};
+use std::cmp::Ordering;
+use std::fmt;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// A calendar date, exchanged as YYYY-MM-DD
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Date {
+    year: i32,
+    month: u32,
+    day: u32,
+}
+
+impl Date {
+    pub fn new(year: i32, month: u32, day: u32) -> Date {
+        Date { year, month, day }
+    }
+
+    pub fn today() -> Date {
+        let secs = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        Date::from_days((secs / 86_400) as i64)
+    }
+
+    /// Converts days since 1970-01-01 to a date in the proleptic Gregorian calendar
+    fn from_days(days: i64) -> Date {
+        let z = days + 719_468;
+        let era = z.div_euclid(146_097);
+        let doe = z.rem_euclid(146_097);
+        let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+        let year = (yoe + era * 400) as i32 + if month <= 2 { 1 } else { 0 };
+        Date { year, month, day }
+    }
+
+    /// Days since 1970-01-01, the inverse of `from_days`
+    fn days(&self) -> i64 {
+        let year = self.year as i64 - if self.month <= 2 { 1 } else { 0 };
+        let era = year.div_euclid(400);
+        let yoe = year.rem_euclid(400);
+        let month = self.month as i64;
+        let doy =
+            (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + self.day as i64 - 1;
+        let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+        era * 146_097 + doe - 719_468
+    }
+
+    /// Number of days from this date until `later`, negative if `later` is earlier
+    pub fn days_until(&self, later: Date) -> i64 {
+        later.days() - self.days()
+    }
+
+    fn parse(s: &str) -> Option<Date> {
+        let mut parts = s.trim().splitn(3, '-');
+        let year = parts.next()?.parse().ok()?;
+        let month = parts.next()?.parse().ok()?;
+        let day = parts.next()?.parse().ok()?;
+        if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+            return None;
+        }
+        Some(Date { year, month, day })
+    }
+
+    /// Shares acquired on this date are long-term holdings once held for more than a year
+    pub fn is_long_term(&self, sold: Date) -> bool {
+        let anniversary = Date {
+            year: self.year + 1,
+            ..*self
+        };
+        sold > anniversary
+    }
+}
+
+impl fmt::Display for Date {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:04}-{:02}-{:02}", self.year, self.month, self.day)
+    }
+}
+
+impl Serialize for Date {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Date {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Date, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        Date::parse(&s)
+            .ok_or_else(|| de::Error::custom(format!("invalid date {}, use YYYY-MM-DD", s)))
+    }
+}
+
+/// Shares of a fund bought together, `cost_basis` is the price paid per share
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Lot {
+    acquired: Date,
+    shares: Decimal,
+    cost_basis: Decimal,
+}
+
+impl Lot {
+    pub fn new(acquired: Date, shares: Decimal, cost_basis: Decimal) -> Lot {
+        Lot {
+            acquired,
+            shares,
+            cost_basis,
+        }
+    }
+
+    pub fn is_valid(&self) -> bool {
+        !self.shares.is_sign_negative() && !self.cost_basis.is_sign_negative()
+    }
+
+    pub fn acquired(&self) -> Date {
+        self.acquired
+    }
+
+    pub fn shares(&self) -> Decimal {
+        self.shares
+    }
+
+    /// Gain per share if sold at `price`
+    pub fn gain(&self, price: Decimal) -> Decimal {
+        price - self.cost_basis
+    }
+
+    /// Gain per share, with long-term gains discounted by their lower tax rate
+    fn weighted_gain(&self, price: Decimal, date: Date) -> Decimal {
+        if self.acquired.is_long_term(date) {
+            self.gain(price) * LONG_TERM_WEIGHT
+        } else {
+            self.gain(price)
+        }
+    }
+}
+
+/// Long-term gains are taxed at roughly half the rate of short-term gains
+const LONG_TERM_WEIGHT: Decimal = dec!(0.5);
+
+/// How to pick which lots are sold first
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LotSelection {
+    /// oldest lots first, what most brokers do by default
+    #[default]
+    Fifo,
+    /// highest cost basis first
+    Hifo,
+    /// smallest gain first, counting long-term gains at their lower tax rate
+    LowestGain,
+    /// short-term then long-term losses (biggest first), then the smallest gains
+    LossFirst,
+    /// long-term lots first, highest cost basis first within each holding period
+    AvoidShortTerm,
+}
+
+impl LotSelection {
+    fn order(self, lots: &mut [Lot], price: Decimal, date: Date) {
+        let by_cost = |a: &Lot, b: &Lot| b.cost_basis.cmp(&a.cost_basis);
+        lots.sort_by(|a, b| {
+            let first = match self {
+                LotSelection::Fifo => Ordering::Equal,
+                LotSelection::Hifo => by_cost(a, b),
+                LotSelection::LowestGain => a
+                    .weighted_gain(price, date)
+                    .cmp(&b.weighted_gain(price, date)),
+                LotSelection::LossFirst => {
+                    let category = |l: &Lot| match l.gain(price).is_sign_negative() {
+                        true if !l.acquired.is_long_term(date) => 0,
+                        true => 1,
+                        false => 2,
+                    };
+                    category(a).cmp(&category(b)).then_with(|| {
+                        a.weighted_gain(price, date)
+                            .cmp(&b.weighted_gain(price, date))
+                    })
+                }
+                LotSelection::AvoidShortTerm => {
+                    let short_term = |l: &Lot| !l.acquired.is_long_term(date);
+                    short_term(a)
+                        .cmp(&short_term(b))
+                        .then_with(|| by_cost(a, b))
+                }
+            };
+            first.then_with(|| a.acquired.cmp(&b.acquired))
+        });
+    }
+}
+
+/// The part of a lot consumed by a sale, enough to submit a specific-ID order
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LotSale {
+    acquired: Date,
+    shares: Decimal,
+    cost_basis: Decimal,
+    gain: Decimal,
+    long_term: bool,
+}
+
+impl LotSale {
+    pub fn shares(&self) -> Decimal {
+        self.shares
+    }
+
+    fn merge(&mut self, other: &LotSale) -> bool {
+        if self.acquired != other.acquired || self.cost_basis != other.cost_basis {
+            return false;
+        }
+        self.shares += other.shares;
+        self.gain += other.gain;
+        true
+    }
+
+    /// Rounds to the reported precision
+    pub fn round(&mut self) {
+        self.shares = money::shares(self.shares);
+        self.cost_basis = money::cents(self.cost_basis);
+        self.gain = money::cents(self.gain);
+    }
+}
+
+/// Adds `sales` to `into`, combining sales from the same lot
+pub fn merge_sales(into: &mut Vec<LotSale>, sales: &[LotSale]) {
+    for sale in sales {
+        if !into.iter_mut().any(|s| s.merge(sale)) {
+            into.push(sale.clone());
+        }
+    }
+}
+
+/// Holdings of a single fund, either a plain share count or the individual lots
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Position {
+    Shares(Decimal),
+    Lots(Vec<Lot>),
+}
+
+impl Position {
+    pub fn shares(&self) -> Decimal {
+        match self {
+            Position::Shares(shares) => *shares,
+            Position::Lots(lots) => lots.iter().map(|l| l.shares).sum(),
+        }
+    }
+
+    pub fn lots(&self) -> Option<&Vec<Lot>> {
+        match self {
+            Position::Shares(_) => None,
+            Position::Lots(lots) => Some(lots),
+        }
+    }
+}
+
+impl From<Decimal> for Position {
+    fn from(shares: Decimal) -> Position {
+        Position::Shares(shares)
+    }
+}
+
+/// Realized capital gains, negative amounts are losses
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct Gains {
+    short_term: Decimal,
+    long_term: Decimal,
+}
+
+impl Gains {
+    pub fn new(short_term: Decimal, long_term: Decimal) -> Gains {
+        Gains {
+            short_term,
+            long_term,
+        }
+    }
+
+    pub fn total(&self) -> Decimal {
+        self.short_term + self.long_term
+    }
+
+    pub fn add(&mut self, other: &Gains) {
+        self.short_term += other.short_term;
+        self.long_term += other.long_term;
+    }
+
+    /// Rounds to the cent
+    pub fn round(&mut self) {
+        self.short_term = money::cents(self.short_term);
+        self.long_term = money::cents(self.long_term);
+    }
+
+    pub fn realized(sales: &[LotSale]) -> Gains {
+        let mut gains = Gains::default();
+        for sale in sales {
+            if sale.long_term {
+                gains.long_term += sale.gain;
+            } else {
+                gains.short_term += sale.gain;
+            }
+        }
+        gains
+    }
+}
+
+/// Limits on the gains a run may realize, in dollars
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct GainsBudget {
+    total: Option<Decimal>,
+    short_term: Option<Decimal>,
+    long_term: Option<Decimal>,
+}
+
+impl GainsBudget {
+    pub fn new(
+        total: Option<Decimal>,
+        short_term: Option<Decimal>,
+        long_term: Option<Decimal>,
+    ) -> Self {
+        GainsBudget {
+            total,
+            short_term,
+            long_term,
+        }
+    }
+
+    pub fn allows(&self, realized: &Gains) -> bool {
+        let within = |limit: Option<Decimal>, gain: Decimal| limit.is_none_or(|l| gain <= l);
+        within(self.total, realized.total())
+            && within(self.short_term, realized.short_term)
+            && within(self.long_term, realized.long_term)
+    }
+}
+
+/// Adds newly bought shares, folding them into a lot bought the same day at the same price
+pub fn buy(lots: &mut Vec<Lot>, shares: Decimal, price: Decimal, date: Date) {
+    let existing = lots
+        .iter_mut()
+        .find(|l| l.acquired == date && l.cost_basis == price);
+    match existing {
+        Some(lot) => lot.shares += shares,
+        None => lots.push(Lot::new(date, shares, price)),
+    }
+}
+
+/// Takes back shares added by `buy`
+pub fn cancel_buy(lots: &mut Vec<Lot>, shares: Decimal, price: Decimal, date: Date) {
+    if let Some(lot) = lots
+        .iter_mut()
+        .find(|l| l.acquired == date && l.cost_basis == price)
+    {
+        lot.shares -= shares;
+    }
+    lots.retain(|l| l.shares > Decimal::ZERO);
+}
+
+/// Removes `shares` from the lots in the order picked by `selection`, returning what was sold
+pub fn sell(
+    lots: &mut Vec<Lot>,
+    shares: Decimal,
+    price: Decimal,
+    date: Date,
+    selection: LotSelection,
+) -> Vec<LotSale> {
+    selection.order(lots, price, date);
+    let mut sales = vec![];
+    let mut remaining = shares;
+    for lot in lots.iter_mut() {
+        if remaining <= Decimal::ZERO {
+            break;
+        }
+        let sold = remaining.min(lot.shares);
+        sales.push(LotSale {
+            acquired: lot.acquired,
+            shares: sold,
+            cost_basis: lot.cost_basis,
+            gain: sold * lot.gain(price),
+            long_term: lot.acquired.is_long_term(date),
+        });
+        lot.shares -= sold;
+        remaining -= sold;
+    }
+    lots.retain(|l| l.shares > Decimal::ZERO);
+    sales
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use spectral::prelude::*;
+
+    #[test]
+    fn date_parsing() {
+        assert_that(&Date::parse("2020-02-29")).is_equal_to(Some(Date::new(2020, 2, 29)));
+        assert_that(&Date::parse("2020-13-01")).is_none();
+        assert_that(&Date::parse("last tuesday")).is_none();
+        assert_that(&Date::new(2020, 3, 1).to_string()).is_equal_to("2020-03-01".to_string());
+        assert_that(&Date::from_days(0)).is_equal_to(Date::new(1970, 1, 1));
+        assert_that(&Date::from_days(18_322)).is_equal_to(Date::new(2020, 3, 1));
+        assert_that(&Date::new(2020, 3, 1).days()).is_equal_to(18_322);
+        assert_that(&Date::new(2019, 12, 15).days_until(Date::new(2020, 1, 14))).is_equal_to(30);
+    }
+
+    #[test]
+    fn holding_period() {
+        let bought = Date::new(2019, 3, 1);
+        assert!(!bought.is_long_term(Date::new(2019, 12, 31)));
+        assert!(!bought.is_long_term(Date::new(2020, 3, 1)));
+        assert!(bought.is_long_term(Date::new(2020, 3, 2)));
+    }
+
+    #[test]
+    fn sell_oldest_lots_first() {
+        let today = Date::new(2020, 6, 1);
+        let mut lots = vec![
+            Lot::new(Date::new(2020, 1, 1), dec!(10), dec!(12)),
+            Lot::new(Date::new(2018, 1, 1), dec!(5), dec!(5)),
+        ];
+
+        let sales = sell(&mut lots, dec!(8), dec!(10), today, LotSelection::Fifo);
+
+        // 5 long-term shares at a $5 gain each, 3 short-term at a $2 loss each
+        assert_that(&sales).has_length(2);
+        assert_that(&Gains::realized(&sales)).is_equal_to(Gains::new(dec!(-6), dec!(25)));
+        assert_that(&lots).is_equal_to(vec![Lot::new(Date::new(2020, 1, 1), dec!(7), dec!(12))]);
+
+        buy(&mut lots, dec!(2), dec!(10), today);
+        buy(&mut lots, dec!(1), dec!(10), today);
+        assert_that(&lots).has_length(2);
+        assert_that(&lots[1]).is_equal_to(Lot::new(today, dec!(3), dec!(10)));
+    }
+
+    fn sold_from(selection: LotSelection) -> Vec<Date> {
+        let mut lots = vec![
+            Lot::new(Date::new(2018, 1, 1), dec!(1), dec!(4)), // long-term, $6 gain
+            Lot::new(Date::new(2019, 1, 1), dec!(1), dec!(11)), // long-term, $1 loss
+            Lot::new(Date::new(2020, 1, 1), dec!(1), dec!(6)), // short-term, $4 gain
+            Lot::new(Date::new(2020, 2, 1), dec!(1), dec!(9)), // short-term, $1 gain
+            Lot::new(Date::new(2020, 3, 1), dec!(1), dec!(10.5)), // short-term, $0.50 loss
+        ];
+        let sales = sell(
+            &mut lots,
+            dec!(5),
+            dec!(10),
+            Date::new(2020, 6, 1),
+            selection,
+        );
+        sales.iter().map(|s| s.acquired).collect()
+    }
+
+    #[test]
+    fn lot_selection_order() {
+        let d = |y, m| Date::new(y, m, 1);
+        let fifo = vec![d(2018, 1), d(2019, 1), d(2020, 1), d(2020, 2), d(2020, 3)];
+        assert_that(&sold_from(LotSelection::Fifo)).is_equal_to(fifo);
+        let hifo = vec![d(2019, 1), d(2020, 3), d(2020, 2), d(2020, 1), d(2018, 1)];
+        assert_that(&sold_from(LotSelection::Hifo)).is_equal_to(hifo);
+        let lowest_gain = vec![d(2019, 1), d(2020, 3), d(2020, 2), d(2018, 1), d(2020, 1)];
+        assert_that(&sold_from(LotSelection::LowestGain)).is_equal_to(lowest_gain);
+        let loss_first = vec![d(2020, 3), d(2019, 1), d(2020, 2), d(2018, 1), d(2020, 1)];
+        assert_that(&sold_from(LotSelection::LossFirst)).is_equal_to(loss_first);
+        let long_term = vec![d(2019, 1), d(2018, 1), d(2020, 3), d(2020, 2), d(2020, 1)];
+        assert_that(&sold_from(LotSelection::AvoidShortTerm)).is_equal_to(long_term);
+    }
+
+    #[test]
+    fn gains_budget() {
+        let unlimited = GainsBudget::default();
+        assert!(unlimited.allows(&Gains::new(dec!(1e6), dec!(1e6))));
+
+        let budget = GainsBudget::new(Some(dec!(100)), None, Some(dec!(20)));
+        assert!(budget.allows(&Gains::new(dec!(80), dec!(20))));
+        assert!(budget.allows(&Gains::new(dec!(150), dec!(-50))));
+        assert!(!budget.allows(&Gains::new(dec!(90), dec!(20))));
+        assert!(!budget.allows(&Gains::new(dec!(0), dec!(30))));
+    }
+
+    #[test]
+    fn merge_lot_sales() {
+        let mut lots = vec![Lot::new(Date::new(2018, 1, 1), dec!(5), dec!(4))];
+        let today = Date::new(2020, 6, 1);
+        let mut sales = sell(&mut lots, dec!(1), dec!(10), today, LotSelection::Fifo);
+        let more = sell(&mut lots, dec!(2), dec!(10), today, LotSelection::Fifo);
+        merge_sales(&mut sales, &more);
+
+        assert_that(&sales).has_length(1);
+        assert_that(&sales[0].shares).is_equal_to(dec!(3));
+        assert_that(&Gains::realized(&sales)).is_equal_to(Gains::new(dec!(0), dec!(18)));
+    }
+}
@@ -1,154 +1,745 @@
+pub mod after_tax;
 pub mod balancer;
+pub mod commission;
+pub mod harvest;
+pub mod location;
+pub mod lots;
+pub mod money;
+pub mod optimizer;
+pub mod target;
+pub mod tolerance;
+pub mod validation;
+pub mod withdrawal;
 
-use std::collections::{HashMap, HashSet};
+use after_tax::{AfterTax, AfterTaxView, Discounts};
+use commission::Commission;
+use harvest::{Harvest, RecentBuy};
+use location::{Placement, TaxRates};
+use lots::{Date, Gains, GainsBudget, Lot, LotSale, LotSelection, Position};
+use money::{dec, Decimal};
+use optimizer::{Mode, Penalties};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use target::Target;
+use tolerance::{Band, OutOfBand};
+use validation::{ValidationError, ValidationErrors};
+use withdrawal::Withdrawal;
 
 #[derive(Debug, PartialEq, Serialize, Deserialize)]
 pub struct Portfolio {
-    target: HashMap<String, f32>,
+    target: BTreeMap<String, Target>, // by asset class, or by fund for funds not in a class
+    #[serde(default)]
+    classes: HashMap<String, Vec<String>>, // interchangeable funds, in order of preference
     accounts: Vec<Account>,
     market: Vec<Investment>,
     no_taxed_sales: Option<bool>, // defaults to allowing sales
     no_sale_accounts: HashSet<String>,
+    deposits: Option<HashMap<String, Decimal>>, // invest only new money by account, with no sales
+    withdrawal: Option<Withdrawal>,             // raise cash to take out, instead of balancing
+    as_of: Option<Date>,                        // defaults to today, used for holding periods
+    max_gains: Option<GainsBudget>,             // across all taxable accounts
+    #[serde(default)]
+    substitutes: Vec<Vec<String>>, // funds that can be swapped to harvest losses, within a class
+    #[serde(default)]
+    identical: Vec<Vec<String>>, // funds that are the same security for wash sales
+    #[serde(default)]
+    recent_buys: Vec<RecentBuy>,
+    harvest_min_loss: Option<Decimal>,
+    mode: Option<Mode>,           // defaults to the greedy balancer
+    penalties: Option<Penalties>, // only used when optimizing
+    tolerance: Option<Band>,      // defaults to balancing every target exactly
+    #[serde(default)]
+    tolerances: HashMap<String, Band>, // by target, instead of the overall tolerance
+    tax_rates: Option<TaxRates>,  // marginal rates, for ranking funds by tax drag
+    min_trade: Option<Decimal>,   // smallest order worth placing, in dollars
+    max_trades: Option<usize>,    // orders across all the accounts
+    max_turnover: Option<f32>,    // percent of the portfolio's value that can be sold to rebalance
+    after_tax: Option<AfterTax>,  // also value the portfolio net of the taxes owed on it
 }
 
 impl Portfolio {
     pub fn new() -> Self {
         Portfolio {
-            target: HashMap::new(),
+            target: BTreeMap::new(),
+            classes: HashMap::new(),
             accounts: vec![],
             market: vec![],
             no_taxed_sales: None,
             no_sale_accounts: HashSet::new(),
+            deposits: None,
+            withdrawal: None,
+            as_of: None,
+            max_gains: None,
+            substitutes: vec![],
+            identical: vec![],
+            recent_buys: vec![],
+            harvest_min_loss: None,
+            mode: None,
+            penalties: None,
+            tolerance: None,
+            tolerances: HashMap::new(),
+            tax_rates: None,
+            min_trade: None,
+            max_trades: None,
+            max_turnover: None,
+            after_tax: None,
         }
     }
 
-    pub fn validate(&self) -> Option<&'static str> {
+    /// Checks for every problem that would stop the portfolio from being balanced
+    pub fn validate(&self) -> Option<ValidationErrors> {
+        let mut errors = ValidationErrors::default();
         // make sure the requested allocations add up to 1 (100%)
-        let sum: f32 = self.target.iter().map(|(_, p)| p).sum();
+        let sum: f32 = self.target.values().map(|t| t.weight()).sum();
         if (sum - 1.0).abs() > 0.01 {
-            return Some("Allocations must add up to 1.0");
+            errors.push(ValidationError::AllocationSum { sum });
         }
-        // make sure we were given price info for all allocated and owned stocks
+        // and that every group in the tree is fully split between its children
+        let nodes = target::nodes(&self.target);
+        for node in nodes.iter() {
+            match node.children_sum {
+                Some(sum) if (sum - 1.0).abs() > 0.01 => errors.push(ValidationError::GroupSum {
+                    group: node.name.clone(),
+                    sum,
+                }),
+                _ => (),
+            }
+        }
+        let mut names = HashSet::new();
+        let repeated = nodes
+            .iter()
+            .filter(|n| !names.insert(&n.name))
+            .map(|n| n.name.clone())
+            .collect();
+        errors.push_names(repeated, |symbols| ValidationError::DuplicateTargets {
+            symbols,
+        });
+        // make sure we were given price info for all allocated, owned and classed stocks
         let prices: HashSet<&String> = self.market.iter().map(|i| &i.symbol).collect();
         let shares = self.total_shares();
-        let missing_owned = shares
+        let targets = self.targets();
+        let targeted = targets.keys().flat_map(|c| self.members(c));
+        let classed = self.classes.values().flatten().map(|s| s.as_str());
+        let missing = shares
+            .keys()
+            .map(|s| s.as_str())
+            .chain(targeted)
+            .chain(classed)
+            .filter(|s| !prices.contains(&s.to_string()))
+            .map(|s| s.to_string())
+            .collect();
+        errors.push_names(missing, |symbols| ValidationError::MissingPrices {
+            symbols,
+        });
+        let invalid = self
+            .market
+            .iter()
+            .filter(|i| i.price <= Decimal::ZERO)
+            .map(|i| i.symbol.clone())
+            .collect();
+        errors.push_names(invalid, |symbols| ValidationError::InvalidPrices {
+            symbols,
+        });
+        let invalid_lots = self
+            .accounts
+            .iter()
+            .flat_map(|a| a.positions.iter())
+            .filter(|(_, p)| p.lots().into_iter().flatten().any(|l| !l.is_valid()))
+            .map(|(s, _)| s.clone())
+            .collect();
+        errors.push_names(invalid_lots, |symbols| ValidationError::InvalidLots {
+            symbols,
+        });
+        // a fund can only count towards one asset class
+        let mut members = HashSet::new();
+        let overlapping = self
+            .classes
+            .values()
+            .flatten()
+            .filter(|s| !members.insert(*s))
+            .cloned()
+            .collect();
+        errors.push_names(overlapping, |symbols| ValidationError::OverlappingClasses {
+            symbols,
+        });
+        let empty = self
+            .classes
+            .iter()
+            .filter(|(_, members)| members.is_empty())
+            .map(|(class, _)| class.clone())
+            .collect();
+        errors.push_names(empty, |symbols| ValidationError::EmptyClasses { symbols });
+        // account names are used to report results, so they need to be distinct
+        let mut names = HashSet::new();
+        let duplicates = self
+            .accounts
+            .iter()
+            .filter(|a| !names.insert(&a.name))
+            .map(|a| a.name.clone())
+            .collect();
+        errors.push_names(duplicates, |accounts| ValidationError::DuplicateAccounts {
+            accounts,
+        });
+        let deposits = self.deposits.iter().flatten();
+        let unknown = self
+            .no_sale_accounts
+            .iter()
+            .chain(deposits.clone().map(|(a, _)| a))
+            .chain(self.withdrawal.iter().flat_map(|w| w.accounts()))
+            .filter(|a| !names.contains(a))
+            .cloned()
+            .collect();
+        errors.push_names(unknown, |accounts| ValidationError::UnknownAccounts {
+            accounts,
+        });
+        let invalid_deposits = deposits
+            .filter(|(_, d)| d.is_sign_negative())
+            .map(|(a, _)| a.clone())
+            .collect();
+        errors.push_names(invalid_deposits, |accounts| {
+            ValidationError::InvalidDeposits { accounts }
+        });
+        if let Some(withdrawal) = self.withdrawal.as_ref().filter(|w| !w.is_valid()) {
+            errors.push(ValidationError::InvalidWithdrawal {
+                amount: money::float(withdrawal.amount()),
+            });
+        }
+        let invalid_reserves = self
+            .accounts
             .iter()
-            .map(|(s, _)| s)
-            .filter(|s| !prices.contains(s))
-            .count();
-        let missing_allocated = self
-            .target
+            .filter(|a| a.reserve.as_ref().is_some_and(|r| !r.is_valid()))
+            .map(|a| a.name.clone())
+            .collect();
+        errors.push_names(invalid_reserves, |accounts| {
+            ValidationError::InvalidReserves { accounts }
+        });
+        let invalid_distributions = self
+            .accounts
             .iter()
-            .map(|(s, _)| s)
-            .filter(|s| !prices.contains(s))
-            .count();
-        if missing_allocated > 0 || missing_owned > 0 {
-            return Some("Missing prices for some investments"); // TODO: format this?
+            .filter(|a| {
+                a.required_distribution.is_some_and(|d| {
+                    d <= Decimal::ZERO || a.account_type() != AccountType::Traditional
+                })
+            })
+            .map(|a| a.name.clone())
+            .collect();
+        errors.push_names(invalid_distributions, |accounts| {
+            ValidationError::InvalidDistributions { accounts }
+        });
+        if self.tax_rates.as_ref().is_some_and(|r| !r.is_valid())
+            || self.after_tax.as_ref().is_some_and(|a| !a.is_valid())
+        {
+            errors.push(ValidationError::InvalidTaxRates);
         }
+        let invalid_commissions = self
+            .accounts
+            .iter()
+            .filter(|a| a.commission.as_ref().is_some_and(|c| !c.is_valid()))
+            .map(|a| a.name.clone())
+            .collect();
+        errors.push_names(invalid_commissions, |accounts| {
+            ValidationError::InvalidCommissions { accounts }
+        });
+        let invalid_increments = self
+            .accounts
+            .iter()
+            .filter(|a| {
+                a.share_increment
+                    .is_some_and(|i| i <= Decimal::ZERO || i > Decimal::ONE)
+            })
+            .map(|a| a.name.clone())
+            .collect();
+        errors.push_names(invalid_increments, |accounts| {
+            ValidationError::InvalidShareIncrement { accounts }
+        });
+        if let Some(percent) = self.max_turnover.filter(|t| !(0.0..=100.0).contains(t)) {
+            errors.push(ValidationError::InvalidTurnover { percent });
+        }
+        let invalid_quotes = self
+            .market
+            .iter()
+            .filter(|i| !i.is_valid_quote())
+            .map(|i| i.symbol.clone())
+            .collect();
+        errors.push_names(invalid_quotes, |symbols| ValidationError::InvalidQuotes {
+            symbols,
+        });
 
-        None
+        if errors.is_empty() {
+            None
+        } else {
+            Some(errors)
+        }
     }
 
-    fn total_value(&self) -> f32 {
-        self.accounts
+    /// Target for each asset class or fund, as a fraction of the whole portfolio
+    fn targets(&self) -> BTreeMap<String, f32> {
+        target::flatten(&self.target)
+    }
+
+    fn total_value(&self) -> Decimal {
+        self.accounts.iter().map(|a| a.value(&self.market)).sum()
+    }
+
+    /// Value that's available to allocate to the targets, after setting aside cash reserves
+    fn investable_value(&self) -> Decimal {
+        self.total_value()
+            - self
+                .accounts
+                .iter()
+                .map(|a| a.reserve(&self.market))
+                .sum::<Decimal>()
+    }
+
+    /// The asset class a fund counts towards, or the fund itself if it isn't in a class
+    fn class_of<'a>(&'a self, symbol: &'a str) -> &'a str {
+        self.classes
             .iter()
-            .map(|a| a.value(&self.market))
-            .sum::<f32>()
+            .find(|(_, members)| members.iter().any(|s| s == symbol))
+            .map_or(symbol, |(class, _)| class.as_str())
+    }
+
+    /// Funds that can be held for a target, in order of preference
+    fn members<'a>(&'a self, class: &'a str) -> Vec<&'a str> {
+        match self.classes.get(class) {
+            Some(members) => members.iter().map(|s| s.as_str()).collect(),
+            None => vec![class],
+        }
+    }
+
+    /// The first fund in the class that the account can buy
+    fn fund_for<'a>(&'a self, account: &Account, class: &'a str) -> Option<&'a str> {
+        self.members(class)
+            .into_iter()
+            .find(|s| account.allows(s) && self.market.iter().any(|i| &i.symbol == s))
     }
 
-    fn total_shares(&self) -> HashMap<String, f32> {
+    fn total_shares(&self) -> HashMap<String, Decimal> {
         let mut tot_shares = HashMap::new();
         for a in self.accounts.iter() {
-            for (sym, shares) in a.positions.iter() {
-                let current = tot_shares.entry(sym.clone()).or_insert(0.0);
-                *current += shares;
+            for (sym, position) in a.positions.iter() {
+                let current = tot_shares.entry(sym.clone()).or_insert(Decimal::ZERO);
+                *current += position.shares();
             }
         }
         tot_shares
     }
 
+    /// Funds that count as the same security as `symbol` for wash sales, including itself
+    fn identical_to<'a>(&'a self, symbol: &'a str) -> HashSet<&'a str> {
+        let mut identical: HashSet<&str> = self
+            .identical
+            .iter()
+            .filter(|group| group.iter().any(|s| s == symbol))
+            .flat_map(|group| group.iter().map(|s| s.as_str()))
+            .collect();
+        identical.insert(symbol);
+        identical
+    }
+
+    /// Funds that can replace `symbol` without changing exposure, in the order they're listed
+    fn substitutes_for(&self, symbol: &str) -> Vec<&String> {
+        let identical = self.identical_to(symbol);
+        self.substitutes
+            .iter()
+            .filter(|group| group.iter().any(|s| s == symbol))
+            .flat_map(|group| group.iter())
+            .filter(|s| !identical.contains(s.as_str()))
+            .collect()
+    }
+
     fn can_sell_taxed(&self) -> bool {
         match self.no_taxed_sales {
             Some(no_sales) => !no_sales,
             None => true,
         }
     }
+
+    /// Whether anything may be sold out of the account at all
+    fn allows_sales(&self, account: &Account) -> bool {
+        self.allows_required_sales(account) && self.deposits.is_none()
+    }
+
+    /// Whether the account may sell to raise cash that has to come out of it, like a required
+    /// distribution, even when only deposits are being invested
+    fn allows_required_sales(&self, account: &Account) -> bool {
+        (account.is_sheltered() || self.can_sell_taxed())
+            && !self.no_sale_accounts.contains(&account.name)
+    }
+
+    /// Adds any deposits to the cash in each account
+    fn deposit(&mut self) {
+        let deposits = match &self.deposits {
+            Some(deposits) => deposits,
+            None => return,
+        };
+        for account in self.accounts.iter_mut() {
+            account.cash += deposits.get(&account.name).cloned().unwrap_or_default();
+        }
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Account {
     name: String,
+    #[serde(default)]
     tax_sheltered: bool,
-    cash: f32,
-    positions: HashMap<String, f32>,
+    account_type: Option<AccountType>, // defaults to traditional if tax sheltered, else taxable
+    cash: Decimal,
+    positions: BTreeMap<String, Position>,
+    lot_selection: Option<LotSelection>, // defaults to FIFO
+    max_gains: Option<GainsBudget>,
+    fractional_shares: Option<bool>,  // defaults to whole shares only
+    share_increment: Option<Decimal>, // smallest fraction of a share the broker will trade
+    reserve: Option<CashReserve>,     // defaults to investing all the cash
+    allowed: Option<HashSet<String>>, // funds the account can buy, defaults to any in the market
+    required_distribution: Option<Decimal>, // RMD to take out this year, traditional accounts only
+    commission: Option<Commission>,   // defaults to trading for free
+    #[serde(default)]
+    commission_free: HashSet<String>, // funds the account trades without a commission
+    max_trades: Option<usize>,        // orders in the account, defaults to no limit
+}
+
+/// How an account is taxed
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AccountType {
+    Taxable,
+    #[serde(alias = "ira", alias = "401k")]
+    Traditional, // taxed on withdrawal, with required minimum distributions
+    #[serde(alias = "roth_ira", alias = "roth_401k")]
+    Roth,
+    Hsa,
+    #[serde(alias = "529")]
+    Education,
+}
+
+impl AccountType {
+    /// Where the account type ranks for holding a kind of fund, lowest first
+    pub fn preference(self, placement: Placement) -> usize {
+        use AccountType::*;
+        let order = match placement {
+            Placement::Income => [Traditional, Hsa, Education, Roth, Taxable],
+            Placement::Growth => [Roth, Hsa, Education, Taxable, Traditional],
+            Placement::Efficient => [Taxable, Roth, Hsa, Education, Traditional],
+        };
+        order.iter().position(|t| *t == self).unwrap_or(order.len())
+    }
 }
 
+/// Cash an account always keeps on hand, never spent by the balancer
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum CashReserve {
+    Dollars(Decimal),
+    Percent { percent: f32 }, // of the account's value, e.g. 5.0 for 5%
+}
+
+impl CashReserve {
+    fn is_valid(&self) -> bool {
+        match self {
+            CashReserve::Dollars(dollars) => !dollars.is_sign_negative(),
+            CashReserve::Percent { percent } => (0.0..=100.0).contains(percent),
+        }
+    }
+}
+
+/// Most brokers that allow fractional shares trade them in thousandths
+const DEFAULT_SHARE_INCREMENT: Decimal = dec!(0.001);
+
 impl Account {
     pub fn new(name: &str) -> Account {
         let name = name.to_owned();
         Account {
             name,
             tax_sheltered: false,
-            cash: 0.0,
-            positions: HashMap::new(),
+            account_type: None,
+            cash: Decimal::ZERO,
+            positions: BTreeMap::new(),
+            lot_selection: None,
+            max_gains: None,
+            fractional_shares: None,
+            share_increment: None,
+            reserve: None,
+            allowed: None,
+            required_distribution: None,
+            commission: None,
+            commission_free: HashSet::new(),
+            max_trades: None,
         }
     }
 
-    fn value(&self, market: &Vec<Investment>) -> f32 {
+    /// The account's type, with `tax_sheltered` accounts treated as traditional
+    fn account_type(&self) -> AccountType {
+        match self.account_type {
+            Some(t) => t,
+            None if self.tax_sheltered => AccountType::Traditional,
+            None => AccountType::Taxable,
+        }
+    }
+
+    /// Whether gains and dividends in the account go untaxed
+    fn is_sheltered(&self) -> bool {
+        self.account_type() != AccountType::Taxable
+    }
+
+    /// What the account charges to trade `symbol`, if anything
+    fn commission(&self, symbol: &str) -> Option<&Commission> {
+        self.commission
+            .as_ref()
+            .filter(|_| !self.commission_free.contains(symbol))
+    }
+
+    /// Whether the account's menu of funds includes `symbol`
+    fn allows(&self, symbol: &str) -> bool {
+        self.allowed.as_ref().is_none_or(|a| a.contains(symbol))
+    }
+
+    /// Dollars of cash the account needs to keep on hand
+    fn reserve(&self, market: &Vec<Investment>) -> Decimal {
+        match self.reserve {
+            Some(CashReserve::Dollars(dollars)) => dollars,
+            Some(CashReserve::Percent { percent }) => {
+                self.value(market) * money::decimal(percent) / dec!(100)
+            }
+            None => Decimal::ZERO,
+        }
+    }
+
+    /// Smallest number of shares the account can trade
+    fn share_increment(&self) -> Decimal {
+        match self.fractional_shares {
+            Some(true) => self.share_increment.unwrap_or(DEFAULT_SHARE_INCREMENT),
+            _ => Decimal::ONE,
+        }
+    }
+
+    /// Rounds down to a number of shares the account can trade
+    fn tradeable(&self, shares: Decimal) -> Decimal {
+        let increment = self.share_increment();
+        match shares.checked_div(increment) {
+            Some(increments) => increments.floor() * increment,
+            None => shares,
+        }
+    }
+
+    fn value(&self, market: &Vec<Investment>) -> Decimal {
         self.cash
             + self
                 .positions
                 .iter()
                 .map(
                     |(sym, pos)| match market.iter().find(|i| &i.symbol == sym) {
-                        Some(info) => pos * info.price,
-                        None => 0.0,
+                        Some(info) => pos.shares() * info.price,
+                        None => Decimal::ZERO,
                     },
                 )
-                .sum::<f32>()
+                .sum::<Decimal>()
     }
 }
 
 #[derive(Debug, PartialEq, Serialize, Deserialize)]
 pub struct Investment {
     symbol: String,
-    price: f32,
+    price: Decimal,
     div_yield: Option<f32>,
+    qualified_ratio: Option<f32>, // share of dividends that are qualified, defaults to none
+    foreign_tax: Option<f32>,     // creditable foreign tax paid, as a fraction of value
+    expected_growth: Option<f32>, // annual, as a fraction of value
+    municipal: Option<bool>,      // pays tax-exempt interest
+    bid: Option<Decimal>,         // what sales fill at, defaults to the price
+    ask: Option<Decimal>,         // what buys fill at, defaults to the price
 }
 
 impl Investment {
-    pub fn new(symbol: &str, price: f32) -> Investment {
+    pub fn new(symbol: &str, price: Decimal) -> Investment {
         Investment {
             symbol: symbol.to_owned(),
             price,
             div_yield: None,
+            qualified_ratio: None,
+            foreign_tax: None,
+            expected_growth: None,
+            municipal: None,
+            bid: None,
+            ask: None,
         }
     }
+
+    pub fn bid(&self) -> Decimal {
+        self.bid.unwrap_or(self.price)
+    }
+
+    pub fn ask(&self) -> Decimal {
+        self.ask.unwrap_or(self.price)
+    }
+
+    fn is_valid_quote(&self) -> bool {
+        [self.bid, self.ask]
+            .iter()
+            .flatten()
+            .all(|q| *q > Decimal::ZERO)
+            && self.bid() <= self.ask()
+    }
 }
 
-#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+    Buy,
+    Sell,
+}
+
+/// A single order to place at the broker, accumulated from the share-at-a-time
+/// transactions the balancer makes
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Trade {
+    account: String,
+    symbol: String,
+    action: Action,
+    shares: Decimal,
+    price: Decimal,
+    gross: Decimal,
+    commission: Decimal,
+    limit: Decimal, // suggested limit price, the ask for buys and the bid for sales
+    lots: Vec<LotSale>, // for sales from accounts that track lots
+}
+
+impl Trade {
+    /// Negative share counts are sales
+    pub fn new(account: &str, symbol: &str, price: Decimal, shares: Decimal) -> Trade {
+        let action = if shares.is_sign_negative() {
+            Action::Sell
+        } else {
+            Action::Buy
+        };
+        Trade {
+            account: account.to_owned(),
+            symbol: symbol.to_owned(),
+            action,
+            shares: shares.abs(),
+            price,
+            gross: (price * shares).abs(),
+            commission: Decimal::ZERO,
+            limit: price,
+            lots: vec![],
+        }
+    }
+
+    fn merge(&mut self, other: &Trade) {
+        self.shares += other.shares;
+        self.gross += other.gross;
+        self.commission += other.commission;
+        // never pay more, or take less, than any part of the order filled at
+        self.limit = match self.action {
+            Action::Buy => self.limit.max(other.limit),
+            Action::Sell => self.limit.min(other.limit),
+        };
+        lots::merge_sales(&mut self.lots, &other.lots);
+        if self.shares > Decimal::ZERO {
+            self.price = self.gross / self.shares;
+        }
+    }
+
+    /// Rounds to the cent, and to the reported share precision
+    fn round(&mut self) {
+        self.shares = money::shares(self.shares);
+        self.price = money::cents(self.price);
+        self.gross = money::cents(self.gross);
+        self.commission = money::cents(self.commission);
+        self.limit = money::cents(self.limit);
+        self.lots.iter_mut().for_each(LotSale::round);
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Results {
-    positions: HashMap<String, HashMap<String, f32>>,
-    allocations: HashMap<String, f32>,
-    cash: HashMap<String, f32>,
-    total_cash: f32,
+    positions: BTreeMap<String, BTreeMap<String, Decimal>>,
+    allocations: BTreeMap<String, f32>,
+    cash: BTreeMap<String, Decimal>, // investable cash, not counting the reserves
+    total_cash: Decimal,
+    reserved: BTreeMap<String, Decimal>,
+    total_reserved: Decimal,
+    drift: BTreeMap<String, f32>, // allocation of invested value minus the target, for every node
+    trades: Vec<Trade>,
+    commissions: Decimal,                // paid across all the trades
+    turnover: f32, // sales to rebalance as a fraction of the portfolio's value
+    capped_drift: BTreeMap<String, f32>, // drift left in each target by the turnover cap
+    gains: BTreeMap<String, Gains>, // realized by sales in taxable accounts
+    harvests: Vec<Harvest>,
+    withdrawals: BTreeMap<String, Decimal>, // cash taken out of each account
+    out_of_band: Vec<OutOfBand>, // targets that were traded for drifting outside their bands
+    after_tax: Option<AfterTaxView>, // when asked for, the allocations net of taxes
+    #[serde(skip)]
+    lots: HashMap<String, HashMap<String, Vec<Lot>>>,
+    #[serde(skip)]
+    accounts: HashMap<String, Account>,
+    #[serde(skip)]
+    as_of: Option<Date>,
+    #[serde(skip)]
+    max_gains: Option<GainsBudget>,
+    #[serde(skip)]
+    reserves: HashMap<String, Decimal>, // dollars each account should be holding back
+    #[serde(skip)]
+    min_trade: Option<Decimal>,
+    #[serde(skip)]
+    max_trades: Option<usize>,
+    #[serde(skip)]
+    max_turnover: Option<Decimal>, // dollars
+    #[serde(skip)]
+    raised: Decimal, // sold to pay withdrawals, which isn't turnover
+    #[serde(skip)]
+    quotes: HashMap<String, (Decimal, Decimal)>, // bid and ask of each fund
+    #[serde(skip)]
+    target: Vec<target::Node>,
+    #[serde(skip)]
+    classes: HashMap<String, String>, // asset class of each fund that's in one
 }
 
 impl Results {
     fn new() -> Self {
         Results {
-            total_cash: 0.0,
-            positions: HashMap::new(),
-            allocations: HashMap::new(),
-            cash: HashMap::new(),
+            total_cash: Decimal::ZERO,
+            positions: BTreeMap::new(),
+            allocations: BTreeMap::new(),
+            cash: BTreeMap::new(),
+            reserved: BTreeMap::new(),
+            total_reserved: Decimal::ZERO,
+            drift: BTreeMap::new(),
+            trades: vec![],
+            commissions: Decimal::ZERO,
+            turnover: 0.0,
+            capped_drift: BTreeMap::new(),
+            gains: BTreeMap::new(),
+            harvests: vec![],
+            withdrawals: BTreeMap::new(),
+            out_of_band: vec![],
+            after_tax: None,
+            lots: HashMap::new(),
+            accounts: HashMap::new(),
+            as_of: None,
+            max_gains: None,
+            reserves: HashMap::new(),
+            min_trade: None,
+            max_trades: None,
+            max_turnover: None,
+            raised: Decimal::ZERO,
+            quotes: HashMap::new(),
+            target: vec![],
+            classes: HashMap::new(),
         }
     }
 
     pub fn from_positions(accounts: &Vec<Account>) -> Results {
         let mut r = Results::new();
         for a in accounts {
-            r.positions.insert(a.name.clone(), a.positions.clone());
+            let shares = a.positions.iter().map(|(s, p)| (s.clone(), p.shares()));
+            // only positions given as lots are tracked, plain share counts have an unknown basis
+            let lots = c! { s.clone() => l.clone(), for (s, l) in a.positions.iter()
+            .filter_map(|(s, p)| p.lots().map(|l| (s, l))) };
+            r.positions.insert(a.name.clone(), shares.collect());
+            r.lots.insert(a.name.clone(), lots);
             r.cash.insert(a.name.clone(), a.cash);
+            r.accounts.insert(a.name.clone(), a.clone());
         }
         r
     }
@@ -157,60 +748,448 @@ impl Results {
         &mut self,
         account: &str,
         symbol: &str,
-        price: f32,
-        shares: f32,
-    ) -> Option<f32> {
-        let cash = self.cash(account, 0.0);
+        price: Decimal,
+        shares: Decimal,
+    ) -> Option<Decimal> {
+        let price = self.fill_price(symbol, price, shares);
         let gross = price * shares;
-        if gross > 0.0 && gross > cash {
+        let commission = self.commission(account, symbol, shares);
+        // sales need to cover their own commission
+        if gross + commission > Decimal::ZERO && gross + commission > self.available_cash(account) {
             return None;
         }
-        self.cash(account, -1.0 * gross);
+        // held funds that aren't on the menu can still be sold
+        if shares > Decimal::ZERO && !self.accounts.get(account).is_none_or(|a| a.allows(symbol)) {
+            return None;
+        }
+        if self.order(account, symbol, shares).is_none() && !self.can_place_order(account) {
+            return None;
+        }
+        if gross < Decimal::ZERO && self.turnover_left().is_some_and(|left| -gross > left) {
+            return None;
+        }
+        self.cash(account, -(gross + commission));
+        let mut trade = Trade::new(account, symbol, price, shares);
+        trade.commission = commission;
+        if shares < Decimal::ZERO {
+            trade.lots = self.sell_lots(account, symbol, price, -shares);
+        } else {
+            self.buy_lots(account, symbol, price, shares);
+        }
         self.transact(account, symbol, shares);
+        self.record(trade);
         Some(gross)
     }
 
-    fn transact(&mut self, account: &str, symbol: &str, shares: f32) -> f32 {
+    fn sell_lots(
+        &mut self,
+        account: &str,
+        symbol: &str,
+        price: Decimal,
+        shares: Decimal,
+    ) -> Vec<LotSale> {
+        let date = self.as_of.unwrap_or_else(Date::today);
+        let selection = self.lot_selection(account);
+        let taxable = self
+            .accounts
+            .get(account)
+            .is_some_and(|a| !a.is_sheltered());
+        let lots = match self.lots.get_mut(account).and_then(|l| l.get_mut(symbol)) {
+            Some(lots) => lots,
+            None => return vec![],
+        };
+        let sales = lots::sell(lots, shares, price, date, selection);
+        if taxable {
+            self.gains
+                .entry(account.to_string())
+                .or_default()
+                .add(&Gains::realized(&sales));
+        }
+        sales
+    }
+
+    /// Gains budgets that apply to sales from the account, along with what's been realized
+    /// against each of them so far
+    fn gains_budgets(&self, account: &str) -> Vec<(&GainsBudget, Gains)> {
+        let mut budgets = vec![];
+        let account = match self.accounts.get(account) {
+            Some(a) if !a.is_sheltered() => a,
+            _ => return budgets,
+        };
+        if let Some(budget) = &account.max_gains {
+            let realized = self.gains.get(&account.name).cloned().unwrap_or_default();
+            budgets.push((budget, realized));
+        }
+        if let Some(budget) = &self.max_gains {
+            let mut realized = Gains::default();
+            self.gains.values().for_each(|g| realized.add(g));
+            budgets.push((budget, realized));
+        }
+        budgets
+    }
+
+    fn lot_selection(&self, account: &str) -> LotSelection {
+        if !self.gains_budgets(account).is_empty() {
+            // stretch the budget as far as it will go
+            return LotSelection::LowestGain;
+        }
+        self.accounts
+            .get(account)
+            .and_then(|a| a.lot_selection)
+            .unwrap_or_default()
+    }
+
+    /// The most shares, up to `shares` and in steps of the account's share increment, that can
+    /// be sold from the account without going over a gains budget. Shares without lots have
+    /// an unknown basis, so they can't be sold from a budgeted account.
+    fn sellable(&self, account: &str, symbol: &str, price: Decimal, shares: Decimal) -> Decimal {
+        let budgets = self.gains_budgets(account);
+        if budgets.is_empty() {
+            return shares;
+        }
+        let lots = match self.lots.get(account).and_then(|l| l.get(symbol)) {
+            Some(lots) => lots,
+            None => return Decimal::ZERO,
+        };
+        let date = self.as_of.unwrap_or_else(Date::today);
+        let fits = |n: Decimal| {
+            let sales = lots::sell(&mut lots.clone(), n, price, date, LotSelection::LowestGain);
+            let gains = Gains::realized(&sales);
+            budgets.iter().all(|(budget, realized)| {
+                let mut total = *realized;
+                total.add(&gains);
+                budget.allows(&total)
+            })
+        };
+        if fits(shares) {
+            return shares;
+        }
+        // selling the lowest gains first, each extra share realizes at least as much gain
+        // as the last, so everything up to the limit fits
+        let increment = self.share_increment(account);
+        let (mut fit, mut over) = (Decimal::ZERO, (shares / increment).floor());
+        while over - fit > Decimal::ONE {
+            let mid = ((fit + over) / dec!(2)).floor();
+            if fits(mid * increment) {
+                fit = mid;
+            } else {
+                over = mid;
+            }
+        }
+        fit * increment
+    }
+
+    fn share_increment(&self, account: &str) -> Decimal {
+        self.accounts
+            .get(account)
+            .map_or(Decimal::ONE, |a| a.share_increment())
+    }
+
+    /// Current value held in each asset class, across all accounts
+    fn class_values(&self, prices: &HashMap<&String, Decimal>) -> HashMap<String, Decimal> {
+        let mut values = HashMap::new();
+        for positions in self.positions.values() {
+            for (sym, shares) in positions.iter() {
+                let price = *prices.get(sym).expect("unexpected missing price");
+                let class = self.classes.get(sym).unwrap_or(sym);
+                *values.entry(class.clone()).or_insert(Decimal::ZERO) += shares * price;
+            }
+        }
+        values
+    }
+
+    /// Total cash taken out of the portfolio so far
+    fn withdrawn(&self) -> Decimal {
+        self.withdrawals.values().sum()
+    }
+
+    /// Cash the account can spend on new shares
+    fn available_cash(&self, account: &str) -> Decimal {
+        self.cash.get(account).cloned().unwrap_or_default()
+    }
+
+    /// Price an order fills at, buying at the ask and selling at the bid when they're known
+    fn fill_price(&self, symbol: &str, price: Decimal, shares: Decimal) -> Decimal {
+        match self.quotes.get(symbol) {
+            Some((bid, _)) if shares < Decimal::ZERO => *bid,
+            Some((_, ask)) => *ask,
+            None => price,
+        }
+    }
+
+    /// How many shares of a fund at `price` to buy in the account, up to `wanted` shares. Whole
+    /// share accounts buy one share at a time, fractional accounts buy up to a share's worth.
+    fn buy_quantity(
+        &self,
+        account: &Account,
+        symbol: &str,
+        price: Decimal,
+        wanted: Decimal,
+    ) -> Decimal {
+        let increment = account.share_increment();
+        if increment >= Decimal::ONE {
+            return Decimal::ONE;
+        }
+        let fill = self.fill_price(symbol, price, Decimal::ONE);
+        let affordable = self.available_cash(&account.name) / fill;
+        account.tradeable(wanted.min(affordable).min(Decimal::ONE))
+    }
+
+    fn buy_lots(&mut self, account: &str, symbol: &str, price: Decimal, shares: Decimal) {
+        let date = self.as_of.unwrap_or_else(Date::today);
+        let held = self.transact(account, symbol, Decimal::ZERO);
+        let account = self.lots.entry(account.to_string()).or_default();
+        // adding a lot to a position with an unknown basis would hide the untracked shares
+        if held > Decimal::ZERO && !account.contains_key(symbol) {
+            return;
+        }
+        lots::buy(
+            account.entry(symbol.to_string()).or_default(),
+            shares,
+            price,
+            date,
+        );
+    }
+
+    /// Extra commission for adding `shares` to the account's order for the fund, negative for
+    /// sales. Each order is charged once, however many transactions it's built from.
+    fn commission(&self, account: &str, symbol: &str, shares: Decimal) -> Decimal {
+        let schedule = match self
+            .accounts
+            .get(account)
+            .and_then(|a| a.commission(symbol))
+        {
+            Some(schedule) => schedule,
+            None => return Decimal::ZERO,
+        };
+        let ordered = self
+            .order(account, symbol, shares)
+            .map_or(Decimal::ZERO, |t| t.shares);
+        schedule.cost(ordered + shares.abs()) - schedule.cost(ordered)
+    }
+
+    /// Value of everything sold so far, other than to pay withdrawals
+    fn sold(&self) -> Decimal {
+        let sold: Decimal = self
+            .trades
+            .iter()
+            .filter(|t| t.action == Action::Sell)
+            .map(|t| t.gross)
+            .sum();
+        sold - self.raised
+    }
+
+    /// Dollars that can still be sold under the turnover cap, if there is one
+    fn turnover_left(&self) -> Option<Decimal> {
+        self.max_turnover
+            .map(|max| (max - self.sold()).max(Decimal::ZERO))
+    }
+
+    /// The account's order so far for the fund, buying or selling as `shares` is
+    fn order(&self, account: &str, symbol: &str, shares: Decimal) -> Option<&Trade> {
+        let action = if shares < Decimal::ZERO {
+            Action::Sell
+        } else {
+            Action::Buy
+        };
+        self.trades
+            .iter()
+            .find(|t| t.account == account && t.symbol == symbol && t.action == action)
+    }
+
+    /// Whether another order fits in the account's and the portfolio's trade limits
+    fn can_place_order(&self, account: &str) -> bool {
+        let in_account = self.trades.iter().filter(|t| t.account == account).count();
+        let account_limit = self.accounts.get(account).and_then(|a| a.max_trades);
+        account_limit.is_none_or(|max| in_account < max)
+            && self.max_trades.is_none_or(|max| self.trades.len() < max)
+    }
+
+    /// Whether a whole order is big enough to place, and its commission is less than the drift
+    /// it fixes. Like `buy_maybe`, the order is valued at the bid or ask it would fill at.
+    fn worth_trading(&self, account: &str, symbol: &str, price: Decimal, shares: Decimal) -> bool {
+        let value = (self.fill_price(symbol, price, shares) * shares).abs();
+        let new_order = self.order(account, symbol, shares).is_none();
+        if new_order && self.min_trade.is_some_and(|min| value < min) {
+            return false;
+        }
+        let commission = self.commission(account, symbol, shares);
+        commission <= Decimal::ZERO || commission < value
+    }
+
+    /// Cancels buy orders smaller than the minimum trade, putting their cash toward the largest
+    /// buy order left in the account instead
+    fn merge_small_trades(&mut self) {
+        let min = match self.min_trade {
+            Some(min) => min,
+            None => return,
+        };
+        let is_small = |t: &Trade| t.action == Action::Buy && t.gross < min;
+        // merging only adds to orders that are already big enough, so this runs out
+        while let Some(trade) = self.trades.iter().find(|t| is_small(t)).cloned() {
+            self.cancel_buy(&trade);
+            let largest = self
+                .trades
+                .iter()
+                .filter(|t| t.account == trade.account && t.action == Action::Buy)
+                .filter(|t| !is_small(t))
+                .max_by_key(|t| t.gross)
+                .map(|t| (t.symbol.clone(), t.price));
+            let account = self.accounts.get(&trade.account).cloned();
+            if let (Some((symbol, price)), Some(account)) = (largest, account) {
+                // only the cancelled order's money moves, not the rest of the account's cash
+                let cash = self.available_cash(&account.name).min(trade.gross);
+                let shares = account.tradeable(cash / price);
+                if shares > Decimal::ZERO {
+                    self.buy_maybe(&account.name, &symbol, price, shares);
+                }
+            }
+        }
+    }
+
+    /// Undoes a buy order entirely
+    fn cancel_buy(&mut self, trade: &Trade) {
+        let date = self.as_of.unwrap_or_else(Date::today);
+        self.transact(&trade.account, &trade.symbol, -trade.shares);
+        if let Some(lots) = self
+            .lots
+            .get_mut(&trade.account)
+            .and_then(|l| l.get_mut(&trade.symbol))
+        {
+            lots::cancel_buy(lots, trade.shares, trade.price, date);
+        }
+        self.cash(&trade.account, trade.gross + trade.commission);
+        self.trades.retain(|t| t != trade);
+    }
+
+    /// Folds the trade into any existing order for the same account, symbol & action
+    fn record(&mut self, trade: Trade) {
+        let existing = self.trades.iter_mut().find(|t| {
+            t.account == trade.account && t.symbol == trade.symbol && t.action == trade.action
+        });
+        match existing {
+            Some(t) => t.merge(&trade),
+            None => self.trades.push(trade),
+        }
+    }
+
+    /// Groups trades by account in the order given, with sales before buys so
+    /// that each account has the cash it needs when the orders are entered
+    fn order_trades(&mut self, accounts: &[Account]) {
+        let position = |name: &str| accounts.iter().position(|a| a.name == name);
+        self.trades.sort_by_key(|t| {
+            let sells_first = match t.action {
+                Action::Sell => 0,
+                Action::Buy => 1,
+            };
+            (position(&t.account), sells_first)
+        });
+    }
+
+    fn transact(&mut self, account: &str, symbol: &str, shares: Decimal) -> Decimal {
         let account = self
             .positions
             .entry(account.to_string())
-            .or_insert(HashMap::new());
-        let current = account.entry(symbol.to_string()).or_insert(0.0);
+            .or_insert(BTreeMap::new());
+        let current = account.entry(symbol.to_string()).or_insert(Decimal::ZERO);
         *current += shares;
-        if *current < 0.0 {
-            *current = 0.0;
+        if *current < Decimal::ZERO {
+            *current = Decimal::ZERO;
         }
         *current
     }
 
-    fn cash(&mut self, account: &str, change: f32) -> f32 {
-        let current = self.cash.entry(account.to_string()).or_insert(0.0);
+    /// Sets aside up to `amount` of the account's cash, topped up from any later sales if the
+    /// account didn't have enough
+    fn reserve_cash(&mut self, account: &str, amount: Decimal) {
+        self.reserves.insert(account.to_string(), amount);
+        let held = self.available_cash(account).min(amount).max(Decimal::ZERO);
+        self.cash(account, -held);
+        self.reserved.insert(account.to_string(), held);
+    }
+
+    fn cash(&mut self, account: &str, change: Decimal) -> Decimal {
+        let mut change = change;
+        if change > Decimal::ZERO {
+            let wanted = self.reserves.get(account).cloned().unwrap_or_default();
+            let reserved = self.reserved.entry(account.to_string()).or_default();
+            let top_up = (wanted - *reserved).max(Decimal::ZERO).min(change);
+            *reserved += top_up;
+            change -= top_up;
+        }
+        let current = self.cash.entry(account.to_string()).or_default();
         *current += change;
         *current
     }
 
-    fn calculate_percentages(&mut self, prices: &HashMap<&String, f32>) {
-        self.total_cash = self.cash.iter().map(|(_, c)| c).sum();
-        let mut total = self.total_cash;
+    fn calculate_percentages(&mut self, prices: &HashMap<&String, Decimal>) {
+        self.total_cash = self.cash.values().sum();
+        self.total_reserved = self.reserved.values().sum();
+        self.commissions = self.trades.iter().map(|t| t.commission).sum();
+        let mut total = self.total_cash + self.total_reserved;
 
+        let mut values: HashMap<String, Decimal> = HashMap::new();
         for (_, positions) in self.positions.iter() {
             for (sym, shares) in positions.iter() {
                 let price = *prices.get(sym).expect("unexpected missing price");
                 let gross = price * shares;
                 total += gross;
-                self.allocations
-                    .entry(sym.to_string())
-                    .and_modify(|g| *g += gross)
-                    .or_insert(gross);
+                let class = self.classes.get(sym).unwrap_or(sym);
+                *values.entry(class.to_string()).or_default() += gross;
             }
         }
 
-        if total > 0.0 {
-            for (_, gross) in self.allocations.iter_mut() {
-                *gross = *gross / total;
+        self.turnover = if total > Decimal::ZERO {
+            money::fraction(self.sold(), total)
+        } else {
+            0.0
+        };
+
+        // drift ignores the reserves, which aren't available to invest
+        let invested = total - self.total_reserved;
+        if invested > Decimal::ZERO {
+            for node in self.target.iter() {
+                let gross: Decimal = node.leaves.iter().filter_map(|l| values.get(l)).sum();
+                self.drift.insert(
+                    node.name.clone(),
+                    money::fraction(gross, invested) - node.weight,
+                );
             }
+        }
+
+        if total > Decimal::ZERO {
+            self.allocations = values
+                .iter()
+                .map(|(c, v)| (c.clone(), money::fraction(*v, total)))
+                .collect();
+            let cash = self.total_cash + self.total_reserved;
             self.allocations
-                .insert(String::from("cash"), self.total_cash / total);
+                .insert(String::from("cash"), money::fraction(cash, total));
+        }
+    }
+
+    /// Rounds cash to the cent and share counts to the reported precision, with the totals
+    /// adding up to what's shown for each account
+    fn round(&mut self) {
+        for shares in self.positions.values_mut().flat_map(|p| p.values_mut()) {
+            *shares = money::shares(*shares);
+        }
+        for cash in self
+            .cash
+            .values_mut()
+            .chain(self.reserved.values_mut())
+            .chain(self.withdrawals.values_mut())
+        {
+            *cash = money::cents(*cash);
+        }
+        self.total_cash = self.cash.values().sum();
+        self.total_reserved = self.reserved.values().sum();
+        self.trades.iter_mut().for_each(Trade::round);
+        self.commissions = self.trades.iter().map(|t| t.commission).sum();
+        self.gains.values_mut().for_each(Gains::round);
+        self.harvests.iter_mut().for_each(Harvest::round);
+        if let Some(view) = self.after_tax.as_mut() {
+            view.round();
         }
     }
 }
@@ -223,15 +1202,29 @@ mod test {
     #[test]
     fn test_portfolio_validation_alloc() {
         let mut portfolio = Portfolio::new();
-        portfolio.market.push(Investment::new("A", 1.0));
-        assert_that(&portfolio.validate())
-            .is_some()
-            .is_equal_to("Allocations must add up to 1.0");
+        portfolio.market.push(Investment::new("A", dec!(1)));
+        assert_that(&validation_errors(&portfolio))
+            .is_equal_to(vec![ValidationError::AllocationSum { sum: 0.0 }]);
 
-        portfolio.target.insert("A".to_string(), 1.001);
+        portfolio.target.insert("A".to_string(), 1.001.into());
         assert_that(&portfolio.validate()).is_none();
     }
 
+    #[test]
+    fn portfolio_substitutes() {
+        let mut p = Portfolio::new();
+        let group = |symbols: &[&str]| symbols.iter().map(|s| s.to_string()).collect();
+        p.substitutes.push(group(&["VTI", "ITOT", "VTSAX"]));
+        p.substitutes.push(group(&["VEU", "VXUS"]));
+        p.identical.push(group(&["VTI", "VTSAX"]));
+
+        assert_that(&p.substitutes_for("VTI")).is_equal_to(vec![&"ITOT".to_string()]);
+        assert_that(&p.substitutes_for("VXUS")).is_equal_to(vec![&"VEU".to_string()]);
+        assert_that(&p.substitutes_for("BND")).is_empty();
+        assert_that(&p.identical_to("VTSAX").len()).is_equal_to(2);
+        assert_that(&p.identical_to("BND").len()).is_equal_to(1);
+    }
+
     #[test]
     fn portfolio_can_sell_taxed() {
         let mut p = Portfolio::new();
@@ -247,50 +1240,386 @@ mod test {
     #[test]
     fn test_portfolio_validation_market() {
         let mut portfolio = Portfolio::new();
-        portfolio.target.insert("B".to_string(), 1.001);
+        portfolio.target.insert("B".to_string(), 1.001.into());
         let mut a = Account::new("a");
-        a.positions.insert("A".to_string(), 5.0);
+        a.positions.insert("A".to_string(), dec!(5).into());
         portfolio.accounts.push(a);
 
-        assert_that(&portfolio.validate())
-            .is_some()
-            .is_equal_to("Missing prices for some investments");
+        assert_that(&validation_errors(&portfolio)).is_equal_to(vec![
+            ValidationError::MissingPrices {
+                symbols: vec!["A".to_string(), "B".to_string()],
+            },
+        ]);
 
-        portfolio.market.push(Investment::new("A", 1.0));
-        portfolio.market.push(Investment::new("B", 1.0));
+        portfolio.market.push(Investment::new("A", dec!(1)));
+        portfolio.market.push(Investment::new("B", dec!(1)));
         assert_that(&portfolio.validate()).is_none();
     }
 
+    #[test]
+    fn test_portfolio_validation_all_errors() {
+        let mut portfolio = Portfolio::new();
+        portfolio.target.insert("A".to_string(), 0.5.into());
+        portfolio.market.push(Investment::new("A", dec!(0)));
+        portfolio.accounts.push(Account::new("a"));
+        portfolio.accounts.push(Account::new("a"));
+        portfolio.no_sale_accounts.insert("ira".to_string());
+        portfolio.accounts[0].reserve = Some(CashReserve::Percent { percent: 150.0 });
+        portfolio.deposits = Some(vec![("a".to_string(), dec!(-1))].into_iter().collect());
+        portfolio.withdrawal = Some(Withdrawal::new(dec!(0), &["b"]));
+        portfolio.accounts[1].required_distribution = Some(dec!(100));
+        portfolio.tax_rates = Some(TaxRates::new(0.24, 1.5));
+        portfolio.accounts[0].commission = Some(Commission::new(dec!(-5), dec!(0)));
+        portfolio.accounts[1].share_increment = Some(dec!(0));
+        portfolio.max_turnover = Some(-3.0);
+        portfolio.market[0].bid = Some(dec!(2));
+
+        let codes: Vec<&str> = validation_errors(&portfolio)
+            .iter()
+            .map(|e| e.code())
+            .collect();
+        assert_that(&codes).is_equal_to(vec![
+            "allocation_sum",
+            "invalid_prices",
+            "duplicate_accounts",
+            "unknown_accounts",
+            "invalid_deposits",
+            "invalid_withdrawal",
+            "invalid_reserves",
+            "invalid_distributions",
+            "invalid_tax_rates",
+            "invalid_commissions",
+            "invalid_share_increment",
+            "invalid_turnover",
+            "invalid_quotes",
+        ]);
+    }
+
+    #[test]
+    fn test_portfolio_validation_share_increment() {
+        let mut portfolio = Portfolio::new();
+        portfolio.target.insert("A".to_string(), 1.0.into());
+        portfolio.market.push(Investment::new("A", dec!(1)));
+        let mut a = Account::new("a");
+        a.fractional_shares = Some(true);
+        a.share_increment = Some(dec!(0.5));
+        portfolio.accounts.push(a);
+        assert_that(&portfolio.validate()).is_none();
+
+        // a zero increment would never let any shares be traded
+        for increment in &[dec!(0), dec!(-0.1), dec!(1.5)] {
+            portfolio.accounts[0].share_increment = Some(*increment);
+            assert_that(&validation_errors(&portfolio)).is_equal_to(vec![
+                ValidationError::InvalidShareIncrement {
+                    accounts: vec!["a".to_string()],
+                },
+            ]);
+        }
+    }
+
+    #[test]
+    fn test_portfolio_validation_tree() {
+        let mut p = Portfolio::new();
+        p.target = serde_json::from_str(
+            r#"{"equity": {"weight": 0.6, "children": {"A": 0.5, "B": 0.4}},
+            "bonds": {"weight": 0.4, "children": {"A": 1.0}}}"#,
+        )
+        .unwrap();
+        p.market.push(Investment::new("A", dec!(1)));
+        p.market.push(Investment::new("B", dec!(1)));
+
+        assert_that(&validation_errors(&p)).is_equal_to(vec![
+            ValidationError::GroupSum {
+                group: "equity".to_string(),
+                sum: 0.9,
+            },
+            ValidationError::DuplicateTargets {
+                symbols: vec!["A".to_string()],
+            },
+        ]);
+    }
+
+    #[test]
+    fn portfolio_classes() {
+        let mut p = Portfolio::new();
+        p.target.insert("sp500".to_string(), 1.0.into());
+        p.classes.insert(
+            "sp500".to_string(),
+            vec!["VOO".to_string(), "FXAIX".to_string()],
+        );
+        p.market.push(Investment::new("VOO", dec!(10)));
+        assert_that(&validation_errors(&p)).is_equal_to(vec![ValidationError::MissingPrices {
+            symbols: vec!["FXAIX".to_string()],
+        }]);
+
+        p.market.push(Investment::new("FXAIX", dec!(20)));
+        assert_that(&p.class_of("FXAIX")).is_equal_to("sp500");
+        assert_that(&p.class_of("B")).is_equal_to("B");
+        let mut a = Account::new("401k");
+        a.allowed = Some(vec!["FXAIX".to_string()].into_iter().collect());
+        assert_that(&p.fund_for(&a, "sp500")).is_equal_to(Some("FXAIX"));
+        assert_that(&p.fund_for(&Account::new("taxed"), "sp500")).is_equal_to(Some("VOO"));
+
+        // funds in untargeted classes need prices too, since they can still be sold
+        p.classes.insert(
+            "total".to_string(),
+            vec!["VTI".to_string(), "VOO".to_string()],
+        );
+        assert_that(&validation_errors(&p)).is_equal_to(vec![
+            ValidationError::MissingPrices {
+                symbols: vec!["VTI".to_string()],
+            },
+            ValidationError::OverlappingClasses {
+                symbols: vec!["VOO".to_string()],
+            },
+        ]);
+
+        // a class without any funds can't be bought
+        p.classes.remove("total");
+        p.classes.insert("bonds".to_string(), vec![]);
+        assert_that(&validation_errors(&p)).is_equal_to(vec![ValidationError::EmptyClasses {
+            symbols: vec!["bonds".to_string()],
+        }]);
+    }
+
+    fn validation_errors(portfolio: &Portfolio) -> Vec<ValidationError> {
+        match portfolio.validate() {
+            Some(errors) => errors.errors().to_vec(),
+            None => vec![],
+        }
+    }
+
+    #[test]
+    fn account_reserve_format() {
+        let a: Account = serde_json::from_str(
+            r#"{"name": "hsa", "tax_sheltered": true, "cash": 100, "positions": {},
+            "reserve": {"percent": 5}}"#,
+        )
+        .unwrap();
+        assert_that(&a.reserve).is_equal_to(Some(CashReserve::Percent { percent: 5.0 }));
+
+        let a: Account = serde_json::from_str(
+            r#"{"name": "hsa", "tax_sheltered": true, "cash": 100, "positions": {},
+            "reserve": 2000}"#,
+        )
+        .unwrap();
+        assert_that(&a.reserve(&vec![])).is_equal_to(dec!(2_000));
+    }
+
+    #[test]
+    fn account_types() {
+        let parse = |json: &str| -> Account {
+            serde_json::from_str(&format!(
+                r#"{{"name": "a", "cash": 0, "positions": {{}}, {}}}"#,
+                json
+            ))
+            .unwrap()
+        };
+        let legacy = parse(r#""tax_sheltered": true"#);
+        assert_that(&legacy.account_type()).is_equal_to(AccountType::Traditional);
+        let typed = parse(r#""account_type": "401k""#);
+        assert_that(&typed.account_type()).is_equal_to(AccountType::Traditional);
+        let roth = parse(r#""account_type": "roth""#);
+        assert_that(&roth.is_sheltered()).is_true();
+        assert_that(&parse(r#""account_type": "529""#).account_type())
+            .is_equal_to(AccountType::Education);
+        assert_that(&parse("\"tax_sheltered\": false").is_sheltered()).is_false();
+
+        let income = AccountType::Traditional.preference(Placement::Income);
+        assert_that(&income).is_less_than(AccountType::Roth.preference(Placement::Income));
+        let efficient = AccountType::Roth.preference(Placement::Efficient);
+        assert_that(&efficient)
+            .is_less_than(AccountType::Traditional.preference(Placement::Efficient));
+    }
+
+    #[test]
+    fn merge_small_trades() {
+        let mut a = Account::new("a");
+        a.cash = dec!(1_000);
+        let mut r = Results::from_positions(&vec![a]);
+        r.min_trade = Some(dec!(100));
+        r.buy_maybe("a", "A", dec!(10), dec!(20));
+        r.buy_maybe("a", "B", dec!(50), dec!(1));
+
+        r.merge_small_trades();
+
+        // the $50 order of B goes to A instead, and the rest of the cash stays put
+        assert_that(&r.trades).has_length(1);
+        assert_that(&r.positions["a"]["A"]).is_equal_to(dec!(25));
+        assert_that(&r.positions["a"]["B"]).is_equal_to(dec!(0));
+        assert_that(&r.available_cash("a")).is_equal_to(dec!(750));
+
+        // only the open order can grow once the limit is reached
+        r.max_trades = Some(1);
+        assert_that(&r.buy_maybe("a", "B", dec!(10), dec!(1))).is_none();
+        assert_that(&r.buy_maybe("a", "A", dec!(10), dec!(1))).is_some();
+        r.max_trades = None;
+        r.accounts.get_mut("a").unwrap().max_trades = Some(1);
+        assert_that(&r.buy_maybe("a", "B", dec!(10), dec!(1))).is_none();
+    }
+
+    #[test]
+    fn test_result_reserve() {
+        let mut r = Results::new();
+        r.cash.insert("a".to_string(), dec!(100));
+        r.reserve_cash("a", dec!(150));
+        assert_that(&r.available_cash("a")).is_equal_to(dec!(0));
+        assert_that(&r.reserved["a"]).is_equal_to(dec!(100));
+
+        // sale proceeds fill the rest of the reserve before they can be spent
+        r.buy_maybe("a", "A", dec!(10), dec!(-10));
+        assert_that(&r.available_cash("a")).is_equal_to(dec!(50));
+        assert_that(&r.reserved["a"]).is_equal_to(dec!(150));
+    }
+
     #[test]
     fn test_portfolio_shares() {
         let mut portfolio = Portfolio::new();
         let mut a = Account::new("a");
-        a.positions.insert("A".to_string(), 5.0);
-        a.positions.insert("B".to_string(), 10.0);
+        a.positions.insert("A".to_string(), dec!(5).into());
+        a.positions.insert("B".to_string(), dec!(10).into());
         let mut b = Account::new("b");
-        b.positions.insert("B".to_string(), 20.0);
+        b.positions.insert("B".to_string(), dec!(20).into());
         portfolio.accounts.push(a);
         portfolio.accounts.push(b);
 
         let shares = portfolio.total_shares();
-        assert_that(shares.get("A").unwrap()).is_close_to(5.0, 0.001);
-        assert_that(shares.get("B").unwrap()).is_close_to(30.0, 0.001);
+        assert_that(shares.get("A").unwrap()).is_equal_to(dec!(5));
+        assert_that(shares.get("B").unwrap()).is_equal_to(dec!(30));
     }
 
     #[test]
     fn test_account_value() {
         let market = vec![];
         let mut account = Account::new("a");
-        assert_eq!(account.value(&market), 0.0);
+        assert_eq!(account.value(&market), dec!(0));
 
-        account.cash = 1.0;
+        account.cash = dec!(1);
         assert_eq!(account.value(&market), account.cash);
 
-        let market = vec![Investment::new("VEU", 10.0), Investment::new("BD", 100.0)];
-        account.positions.insert("VEU".to_string(), 3.0);
-        account.positions.insert("BD".to_string(), 1.0);
-        account.positions.insert("NO-PRICE".to_string(), 5.0);
-        assert_eq!(account.value(&market), 131.0);
+        let market = vec![
+            Investment::new("VEU", dec!(10)),
+            Investment::new("BD", dec!(100)),
+        ];
+        account.positions.insert("VEU".to_string(), dec!(3).into());
+        account.positions.insert("BD".to_string(), dec!(1).into());
+        account
+            .positions
+            .insert("NO-PRICE".to_string(), dec!(5).into());
+        assert_eq!(account.value(&market), dec!(131));
+    }
+
+    #[test]
+    fn money_format() {
+        let account: Account = serde_json::from_str(
+            r#"{"name": "a", "tax_sheltered": false, "cash": 0.1, "positions": {"A": 2}}"#,
+        )
+        .unwrap();
+        let market = vec![Investment::new("A", dec!(0.1))];
+        assert_that(&account.value(&market)).is_equal_to(dec!(0.3));
+
+        let mut r = Results::new();
+        r.cash.insert("a".to_string(), dec!(10.005));
+        r.positions
+            .entry("a".to_string())
+            .or_default()
+            .insert("A".to_string(), dec!(1.23456789));
+        r.round();
+        let json = serde_json::to_value(&r).unwrap();
+        assert_that(&json["cash"]["a"]).is_equal_to(serde_json::json!(10.01));
+        assert_that(&json["total_cash"]).is_equal_to(serde_json::json!(10.01));
+        assert_that(&json["positions"]["a"]["A"]).is_equal_to(serde_json::json!(1.234568));
+    }
+
+    #[test]
+    fn test_account_share_increments() {
+        let mut account = Account::new("a");
+        assert_that(&account.share_increment()).is_equal_to(dec!(1));
+        assert_that(&account.tradeable(dec!(2.9))).is_equal_to(dec!(2));
+
+        account.fractional_shares = Some(true);
+        assert_that(&account.share_increment()).is_equal_to(dec!(0.001));
+        assert_that(&account.tradeable(dec!(0.7))).is_equal_to(dec!(0.7));
+        assert_that(&account.tradeable(dec!(2.9999))).is_equal_to(dec!(2.999));
+
+        account.share_increment = Some(dec!(0.25));
+        assert_that(&account.tradeable(dec!(2.9))).is_equal_to(dec!(2.75));
+    }
+
+    #[test]
+    fn test_account_lots_format() {
+        let json = r#"{"name": "taxed", "tax_sheltered": false, "cash": 10.0, "positions": {
+            "A": 5,
+            "B": [{"acquired": "2019-01-02", "shares": 2, "cost_basis": 90.5},
+                  {"acquired": "2020-01-02", "shares": 1.5, "cost_basis": 101}]
+        }}"#;
+        let account: Account = serde_json::from_str(json).expect("failed to parse");
+
+        assert_that(&account.positions.get("A").unwrap().shares()).is_equal_to(dec!(5));
+        let b = account.positions.get("B").unwrap();
+        assert_that(&b.shares()).is_equal_to(dec!(3.5));
+        assert_that(&b.lots().unwrap()[0]).is_equal_to(Lot::new(
+            Date::new(2019, 1, 2),
+            dec!(2),
+            dec!(90.5),
+        ));
+    }
+
+    #[test]
+    fn test_result_gains() {
+        let mut taxed = Account::new("taxed");
+        let lots = vec![
+            Lot::new(Date::new(2019, 1, 2), dec!(2), dec!(5)),
+            Lot::new(Date::new(2020, 1, 2), dec!(2), dec!(15)),
+        ];
+        taxed
+            .positions
+            .insert("A".to_string(), Position::Lots(lots.clone()));
+        taxed.positions.insert("B".to_string(), dec!(5).into());
+        let mut ira = Account::new("ira");
+        ira.tax_sheltered = true;
+        ira.positions.insert("A".to_string(), Position::Lots(lots));
+
+        let mut r = Results::from_positions(&vec![taxed, ira]);
+        r.as_of = Some(Date::new(2020, 6, 1));
+        r.buy_maybe("taxed", "A", dec!(10), dec!(-3));
+        r.buy_maybe("taxed", "B", dec!(10), dec!(-1)); // unknown basis
+        r.buy_maybe("ira", "A", dec!(10), dec!(-3));
+
+        assert_that(&r.gains.get("taxed")).is_equal_to(Some(&Gains::new(dec!(-5), dec!(10))));
+        assert_that(&r.gains.get("ira")).is_none();
+
+        // new shares get their own lot, but not if they'd mix with untracked shares
+        r.cash.insert("taxed".to_string(), dec!(100));
+        r.buy_maybe("taxed", "A", dec!(10), dec!(2));
+        r.buy_maybe("taxed", "B", dec!(10), dec!(2));
+        let lots = r.lots.get("taxed").unwrap();
+        assert_that(lots.get("A").unwrap()).has_length(2);
+        assert_that(&lots.get("B")).is_none();
+    }
+
+    #[test]
+    fn test_result_lot_selection() {
+        let mut taxed = Account::new("taxed");
+        taxed.lot_selection = Some(LotSelection::Hifo);
+        let lots = vec![
+            Lot::new(Date::new(2019, 1, 2), dec!(2), dec!(5)),
+            Lot::new(Date::new(2020, 1, 2), dec!(2), dec!(15)),
+        ];
+        taxed
+            .positions
+            .insert("A".to_string(), Position::Lots(lots));
+
+        let mut r = Results::from_positions(&vec![taxed]);
+        r.as_of = Some(Date::new(2020, 6, 1));
+        r.buy_maybe("taxed", "A", dec!(10), dec!(-1));
+        r.buy_maybe("taxed", "A", dec!(10), dec!(-2));
+
+        // the expensive short-term lot is used up first
+        assert_that(&r.gains.get("taxed")).is_equal_to(Some(&Gains::new(dec!(-10), dec!(5))));
+        assert_that(&r.trades).has_length(1);
+        assert_that(&r.trades[0].lots).has_length(2);
+        assert_that(&r.trades[0].lots[0].shares()).is_equal_to(dec!(2));
     }
 
     #[test]
@@ -298,27 +1627,61 @@ mod test {
         let a = String::from("A");
         let b = String::from("B");
         let mut market = HashMap::new();
-        market.insert(&a, 10.0);
-        market.insert(&b, 1.0);
+        market.insert(&a, dec!(10));
+        market.insert(&b, dec!(1));
 
         let mut r = Results::new();
-        r.transact("a1", "A", 1.0);
+        r.transact("a1", "A", dec!(1));
 
         r.calculate_percentages(&market); // TODO: fix type of market
         check_allocation(&r, "A", 1.0);
 
-        r.cash.insert(String::from("a1"), 50.0);
-        r.transact("a2", "A", 3.0);
-        r.transact("a1", "B", 10.0);
+        r.cash.insert(String::from("a1"), dec!(50));
+        r.transact("a2", "A", dec!(3));
+        r.transact("a1", "B", dec!(10));
         r.calculate_percentages(&market);
 
-        check_allocation(&r, "A", 0.41);
+        check_allocation(&r, "A", 0.4);
         check_allocation(&r, "B", 0.1);
         check_allocation(&r, "cash", 0.5);
     }
 
+    #[test]
+    fn test_result_trades() {
+        let mut r = Results::new();
+        r.cash.insert(String::from("a1"), dec!(100));
+        r.transact("a2", "A", dec!(5));
+
+        r.buy_maybe("a1", "A", dec!(10), dec!(1));
+        r.buy_maybe("a2", "A", dec!(10), dec!(-2));
+        r.buy_maybe("a1", "B", dec!(1), dec!(3));
+        r.buy_maybe("a1", "A", dec!(10), dec!(2));
+        r.buy_maybe("a1", "A", dec!(10), dec!(-1));
+        assert_that(&r.buy_maybe("a1", "B", dec!(1), dec!(100))).is_none();
+
+        let accounts = vec![Account::new("a1"), Account::new("a2")];
+        r.order_trades(&accounts);
+
+        // the sale comes from the lot bought earlier, which we don't compare here
+        r.trades[0].lots.clear();
+        assert_that(&r.trades).is_equal_to(vec![
+            Trade::new("a1", "A", dec!(10), dec!(-1)),
+            Trade::new("a1", "A", dec!(10), dec!(3)),
+            Trade::new("a1", "B", dec!(1), dec!(3)),
+            Trade::new("a2", "A", dec!(10), dec!(-2)),
+        ]);
+        assert_that(&r.trades[1].gross).is_equal_to(dec!(30));
+        assert_that(&r.trades[3].action).is_equal_to(Action::Sell);
+    }
+
     pub fn check_allocation(r: &Results, sym: &str, expected: f32) {
         let a = r.allocations.get(sym).expect("missing symbol");
         assert_that(a).is_close_to(expected, 0.001);
     }
+
+    pub fn check_shares(r: &Results, acct: &str, sym: &str, expected: Decimal) {
+        let account = r.positions.get(acct).expect("missing account");
+        let shares = account.get(sym).cloned().unwrap_or_default();
+        assert_that(&shares).is_equal_to(expected);
+    }
 }
@@ -0,0 +1,59 @@
+use rust_decimal::prelude::{FromPrimitive, RoundingStrategy, ToPrimitive};
+pub use rust_decimal::{dec, Decimal};
+
+/// Cash and prices are reported to the cent
+pub const CENTS: u32 = 2;
+/// Share counts are reported to a millionth of a share
+pub const SHARE_PLACES: u32 = 6;
+
+/// A fraction, like a target weight or tax rate, as a decimal to multiply money by
+pub fn decimal(fraction: f32) -> Decimal {
+    Decimal::from_f32(fraction).unwrap_or_default()
+}
+
+/// `part` as a fraction of `whole`, or zero when there's no whole to divide
+pub fn fraction(part: Decimal, whole: Decimal) -> f32 {
+    part.checked_div(whole)
+        .and_then(|f| f.to_f32())
+        .unwrap_or(0.0)
+}
+
+/// Dollars as a plain number, for reporting and for costs that don't need to be exact
+pub fn float(amount: Decimal) -> f32 {
+    amount.to_f32().unwrap_or(0.0)
+}
+
+/// An amount as the solver's floating point number
+pub fn to_solver(amount: Decimal) -> f64 {
+    amount.to_f64().unwrap_or(0.0)
+}
+
+/// A value the solver worked out, as a decimal
+pub fn from_solver(value: f64) -> Decimal {
+    Decimal::from_f64(value).unwrap_or_default()
+}
+
+/// Rounds to the cent, with half a cent rounding away from zero
+pub fn cents(amount: Decimal) -> Decimal {
+    amount.round_dp_with_strategy(CENTS, RoundingStrategy::MidpointAwayFromZero)
+}
+
+/// Rounds to the share precision that's reported
+pub fn shares(shares: Decimal) -> Decimal {
+    shares.round_dp_with_strategy(SHARE_PLACES, RoundingStrategy::MidpointAwayFromZero)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use spectral::prelude::*;
+
+    #[test]
+    fn convert_fractions() {
+        assert_that(&decimal(0.34)).is_equal_to(dec!(0.34));
+        assert_that(&fraction(dec!(1), dec!(3))).is_close_to(0.3333, 0.0001);
+        assert_that(&fraction(dec!(1), Decimal::ZERO)).is_equal_to(0.0);
+        assert_that(&cents(dec!(10.005))).is_equal_to(dec!(10.01));
+        assert_that(&shares(dec!(1.2345678))).is_equal_to(dec!(1.234568));
+    }
+}
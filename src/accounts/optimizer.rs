@@ -0,0 +1,584 @@
+use super::*;
+use microlp::{ComparisonOp, OptimizationDirection, Problem, Variable};
+use std::collections::BTreeSet;
+
+/// Which balancing algorithm `run_balancing` uses
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Mode {
+    /// buy the most needed fund a share at a time
+    #[default]
+    Greedy,
+    /// solve for the set of trades with the lowest overall cost
+    Optimize,
+}
+
+/// What the optimizer is trying to minimize, in dollars, on top of any commissions paid
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Penalties {
+    drift: f32, // per dollar held above or below target
+    tax: f32,   // per dollar of realized gains, and of dividends paid into taxable accounts
+    trade: f32, // per order placed
+}
+
+impl Default for Penalties {
+    fn default() -> Self {
+        Penalties {
+            drift: 1.0,
+            tax: 0.25,
+            trade: 1.0,
+        }
+    }
+}
+
+/// A buy or sale of one fund in one account that the solver can choose to make
+struct Choice<'a> {
+    account: &'a Account,
+    symbol: &'a String,
+    price: Decimal, // what it's valued at
+    fill: Decimal,  // what it trades at, the ask for buys and the bid for sales
+    worth: f64,     // share of the value left after taxes, when balancing after tax
+    sale: bool,
+    commission: (Decimal, Decimal), // fixed and per share
+    cost: f64,                      // per share, with the commission
+    order_cost: f64,                // for placing the order at all, with any fixed commission
+    shares: Variable,
+    placed: Variable, // how much of the order's fixed cost is paid, all of it once it's placed
+}
+
+impl Choice<'_> {
+    /// Cash the trade raises per share, net of the per-share commission
+    fn cash_per_share(&self) -> f64 {
+        let fill = if self.sale { self.fill } else { -self.fill };
+        let (_, per_share) = self.commission;
+        money::to_solver(fill - per_share)
+    }
+
+    /// Value it adds to its asset class per share
+    fn value_per_share(&self) -> f64 {
+        let value = money::to_solver(self.price) * self.worth;
+        if self.sale {
+            -value
+        } else {
+            value
+        }
+    }
+}
+
+/// The linear program the optimizer solves
+struct Model<'a> {
+    problem: Problem,
+    choices: Vec<Choice<'a>>,
+    trade_penalty: f64,
+}
+
+impl<'a> Model<'a> {
+    /// Adds a buy or sale of up to `most` shares valued at `price`, of which `worth` is left
+    /// after taxes, and trading at `fill`. It costs `cost` per share on top of its commission.
+    fn choose(
+        &mut self,
+        account: &'a Account,
+        symbol: &'a String,
+        (price, fill, worth): (Decimal, Decimal, f64),
+        sale: bool,
+        most: Decimal,
+        cost: f64,
+    ) {
+        let commission = account
+            .commission(symbol)
+            .map_or((Decimal::ZERO, Decimal::ZERO), |c| c.linear());
+        let (fixed, per_share) = commission;
+        let cost = cost + money::to_solver(per_share);
+        let order_cost = self.trade_penalty + money::to_solver(fixed);
+        let most = money::to_solver(most);
+        let shares = self.problem.add_var(cost, (0.0, most));
+        let placed = self.problem.add_var(order_cost, (0.0, 1.0));
+        // the order's fixed cost is paid in proportion to how much of the most it could be
+        self.problem
+            .add_constraint([(shares, 1.0), (placed, -most)], ComparisonOp::Le, 0.0);
+        self.choices.push(Choice {
+            account,
+            symbol,
+            price,
+            fill,
+            worth,
+            sale,
+            commission,
+            cost,
+            order_cost,
+            shares,
+            placed,
+        });
+    }
+}
+
+/// Gains realized per share by selling `shares` of the fund with the account's lot selection,
+/// or zero for shares that aren't tracked in lots
+fn gain_per_share(
+    results: &Results,
+    account: &str,
+    symbol: &str,
+    price: Decimal,
+    shares: Decimal,
+) -> Decimal {
+    let lots = match results.lots.get(account).and_then(|l| l.get(symbol)) {
+        Some(lots) => lots,
+        None => return Decimal::ZERO,
+    };
+    let date = results.as_of.unwrap_or_else(Date::today);
+    let selection = results.lot_selection(account);
+    let sales = lots::sell(&mut lots.clone(), shares, price, date, selection);
+    let gains = Gains::realized(&sales).total();
+    gains.checked_div(shares).unwrap_or_default()
+}
+
+/// Balances by solving a linear program for the trades with the lowest overall cost: drift
+/// from the targets, realized gains and tax drag, orders placed and their commissions. The
+/// solution is rounded down to shares each account can trade and placed through
+/// `Results::buy_maybe`, so the same cash and sale rules as the greedy balancer apply to
+/// anything the model only approximates, like commission minimums, gains budgets shared
+/// between holdings or trade limits. Cash left over from rounding is then spent wherever it
+/// lowers the cost. Only targets outside their tolerance bands are sold, and values are net of
+/// taxes owed when balancing after tax.
+pub fn optimize(
+    portfolio: &Portfolio,
+    results: &mut Results,
+    prices: &HashMap<&String, Decimal>,
+    out_of_band: Option<&[OutOfBand]>,
+) {
+    let penalties = portfolio.penalties.clone().unwrap_or_default();
+    let tax = penalties.tax as f64;
+    let after_tax = Discounts::new(portfolio).filter(|d| d.balance());
+    let (held, total_value) = values(portfolio, results, prices, after_tax.as_ref());
+    let targets = c! { c => money::decimal(w) * total_value, for (c, w) in portfolio.targets() };
+    let drags = c! { &i.symbol => i.tax_drag(portfolio.tax_rates.as_ref()) as f64,
+    for i in portfolio.market.iter() };
+    let mut symbols: Vec<&String> = prices.keys().cloned().collect();
+    symbols.sort();
+
+    let mut model = Model {
+        problem: Problem::new(OptimizationDirection::Minimize),
+        choices: vec![],
+        trade_penalty: penalties.trade as f64,
+    };
+    // annual tax on the dividends of each share held in the account
+    let drag = |account: &Account, symbol: &String| match account.is_sheltered() {
+        false => tax * drags[symbol] * money::to_solver(prices[symbol]),
+        true => 0.0,
+    };
+    // the cash and the proceeds of every possible sale bound what each account can buy, and
+    // the value each account could sell of each class bounds what the others can swap into
+    let mut spendable = HashMap::new();
+    let mut for_sale: HashMap<(&String, &str), Decimal> = HashMap::new();
+    for account in portfolio.accounts.iter() {
+        let name = &account.name;
+        let taxable = !account.is_sheltered();
+        let holding = |symbol: &str| {
+            results
+                .positions
+                .get(name)
+                .and_then(|p| p.get(symbol))
+                .cloned()
+                .unwrap_or_default()
+        };
+
+        let cash = spendable
+            .entry(name)
+            .or_insert(results.available_cash(name));
+        if !portfolio.allows_sales(account) {
+            continue;
+        }
+        for symbol in symbols
+            .iter()
+            .filter(|s| tolerance::should_trade(out_of_band, portfolio.class_of(s)))
+        {
+            let price = prices[*symbol];
+            let bid = results.fill_price(symbol, price, -Decimal::ONE);
+            let most = results.sellable(name, symbol, bid, holding(symbol));
+            let most = account.tradeable(most);
+            if most <= Decimal::ZERO {
+                continue;
+            }
+            *cash += most * bid;
+            let gain = match taxable {
+                true => gain_per_share(results, name, symbol, bid, most),
+                false => Decimal::ZERO,
+            };
+            let cost = tax * money::to_solver(gain.max(Decimal::ZERO)) - drag(account, symbol);
+            let worth = after_tax
+                .as_ref()
+                .map_or(1.0, |d| d.holding(results, name, symbol, price));
+            let class = portfolio.class_of(symbol);
+            *for_sale.entry((name, class)).or_default() +=
+                most * price * money::from_solver(worth as f64);
+            let quote = (price, bid, worth as f64);
+            model.choose(account, symbol, quote, true, most, cost);
+        }
+    }
+    for account in portfolio.accounts.iter() {
+        let name = &account.name;
+        let worth = after_tax.as_ref().map_or(1.0, |d| d.account(account)) as f64;
+        for symbol in symbols.iter().filter(|s| account.allows(s)) {
+            // buying more than the class is short, plus what other accounts could sell of it to
+            // make room, only adds drift. Keeping the most an order could be close to what it
+            // would really be also charges small orders most of their fixed cost.
+            let class = portfolio.class_of(symbol);
+            let target = match targets.get(class) {
+                Some(target) => *target,
+                None => continue,
+            };
+            let short = target - held.get(class).cloned().unwrap_or_default();
+            let swappable: Decimal = for_sale
+                .iter()
+                .filter(|((a, c), _)| *a != name && *c == class)
+                .map(|(_, value)| *value)
+                .sum();
+            let room = short.max(Decimal::ZERO) + swappable;
+            let price = prices[*symbol];
+            let ask = results.fill_price(symbol, price, Decimal::ONE);
+            let wanted = room
+                .checked_div(price * money::from_solver(worth))
+                .unwrap_or(room / price);
+            let most = account.tradeable(wanted.min(spendable[name] / ask));
+            if most > Decimal::ZERO {
+                let quote = (price, ask, worth);
+                model.choose(account, symbol, quote, false, most, drag(account, symbol));
+            }
+        }
+    }
+    let Model {
+        mut problem,
+        choices,
+        ..
+    } = model;
+
+    // value above or below the target in each asset class, including any held but untargeted
+    let classes: BTreeSet<&String> = targets.keys().chain(held.keys()).collect();
+    let drift = penalties.drift as f64;
+    for class in classes {
+        let target = targets.get(class).cloned().unwrap_or_default();
+        let value = held.get(class).cloned().unwrap_or_default();
+        let over = problem.add_var(drift, (0.0, f64::INFINITY));
+        let under = problem.add_var(drift, (0.0, f64::INFINITY));
+        let mut terms: Vec<(Variable, f64)> = choices
+            .iter()
+            .filter(|c| portfolio.class_of(c.symbol) == class)
+            .map(|c| (c.shares, c.value_per_share()))
+            .collect();
+        terms.extend([(over, -1.0), (under, 1.0)]);
+        problem.add_constraint(terms, ComparisonOp::Eq, money::to_solver(target - value));
+    }
+
+    // each account pays for its buys and commissions with its cash and sale proceeds
+    for account in portfolio.accounts.iter() {
+        let in_account = || choices.iter().filter(|c| c.account.name == account.name);
+        let mut cash: Vec<(Variable, f64)> = in_account()
+            .map(|c| (c.shares, c.cash_per_share()))
+            .collect();
+        cash.extend(in_account().map(|c| (c.placed, -money::to_solver(c.commission.0))));
+        let available = money::to_solver(results.available_cash(&account.name));
+        problem.add_constraint(cash, ComparisonOp::Ge, -available);
+    }
+    if let Some(left) = results.turnover_left() {
+        let sold: Vec<(Variable, f64)> = choices
+            .iter()
+            .filter(|c| c.sale)
+            .map(|c| (c.shares, money::to_solver(c.fill)))
+            .collect();
+        problem.add_constraint(sold, ComparisonOp::Le, money::to_solver(left));
+    }
+
+    let solution = match problem.solve() {
+        Ok(solution) => solution,
+        Err(e) => {
+            println!("optimizer found no solution: {}", e);
+            return;
+        }
+    };
+    println!("optimizer solved at cost={}", solution.objective());
+
+    // sales first, so their proceeds are there for the buys
+    let (sales, buys): (Vec<&Choice>, Vec<&Choice>) = choices.iter().partition(|c| c.sale);
+    for c in sales.into_iter().chain(buys) {
+        let name = &c.account.name;
+        let solved = money::shares(money::from_solver(solution[c.shares]));
+        let shares = if c.sale {
+            -results.sellable(name, c.symbol, c.fill, c.account.tradeable(solved))
+        } else {
+            // commissions are only estimated, so buy what the cash actually covers
+            let affordable = results.available_cash(name) / c.fill;
+            c.account.tradeable(solved.min(affordable))
+        };
+        if shares != Decimal::ZERO && results.worth_trading(name, c.symbol, c.price, shares) {
+            results.buy_maybe(name, c.symbol, c.price, shares);
+        }
+    }
+    let after_tax = after_tax.as_ref();
+    spend_leftovers(
+        portfolio, results, prices, &targets, &choices, after_tax, drift,
+    );
+
+    // classes still overweight that the turnover cap kept from selling another share
+    let (held, _) = values(portfolio, results, prices, after_tax);
+    if let Some(left) = results.turnover_left() {
+        for (class, value) in held.iter() {
+            let over = *value - targets.get(class).cloned().unwrap_or_default();
+            let capped = choices.iter().any(|c| {
+                c.sale
+                    && portfolio.class_of(c.symbol) == class
+                    && left < c.fill * c.account.share_increment()
+            });
+            if over > Decimal::ZERO && capped {
+                let drift = money::fraction(over, total_value);
+                results.capped_drift.insert(class.clone(), drift);
+            }
+        }
+    }
+}
+
+/// Value of each asset class, and of everything that can be invested, net of taxes owed when
+/// balancing after tax
+fn values(
+    portfolio: &Portfolio,
+    results: &Results,
+    prices: &HashMap<&String, Decimal>,
+    after_tax: Option<&Discounts>,
+) -> (HashMap<String, Decimal>, Decimal) {
+    match after_tax {
+        Some(discounts) => discounts.values(results, prices),
+        None => (
+            results.class_values(prices),
+            portfolio.investable_value() - results.withdrawn(),
+        ),
+    }
+}
+
+/// Spends cash left over from rounding the solution down, buying whatever lowers the cost most
+/// until nothing does
+fn spend_leftovers(
+    portfolio: &Portfolio,
+    results: &mut Results,
+    prices: &HashMap<&String, Decimal>,
+    targets: &HashMap<String, Decimal>,
+    choices: &[Choice],
+    after_tax: Option<&Discounts>,
+    drift: f64,
+) {
+    loop {
+        let (held, _) = values(portfolio, results, prices, after_tax);
+        let mut best: Option<(f64, &Choice, Decimal)> = None;
+        for c in choices.iter().filter(|c| !c.sale) {
+            let name = &c.account.name;
+            let increment = c.account.share_increment();
+            let class = portfolio.class_of(c.symbol);
+            let need = targets.get(class).cloned().unwrap_or_default()
+                - held.get(class).cloned().unwrap_or_default();
+            let cash = results.available_cash(name);
+            if increment <= Decimal::ZERO || cash < increment * c.fill {
+                continue;
+            }
+            // as much as the target needs, or a single increment to get closer to it
+            let shares = c
+                .account
+                .tradeable(need.max(Decimal::ZERO).min(cash) / c.fill)
+                .max(increment);
+            let value = shares * c.price * money::from_solver(c.worth);
+            let fixed = match results.order(name, c.symbol, shares) {
+                Some(_) => 0.0,
+                None => c.order_cost,
+            };
+            let saved = drift * money::to_solver(need.abs() - (need - value).abs())
+                - c.cost * money::to_solver(shares)
+                - fixed;
+            if saved > 0.0
+                && best.as_ref().is_none_or(|(s, _, _)| saved > *s)
+                && results.worth_trading(name, c.symbol, c.price, shares)
+            {
+                best = Some((saved, c, shares));
+            }
+        }
+        match best {
+            Some((_, c, shares))
+                if results
+                    .buy_maybe(&c.account.name, c.symbol, c.price, shares)
+                    .is_some() => {}
+            _ => return,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::accounts::test::check_shares;
+    use crate::run_balancing;
+    use spectral::prelude::*;
+
+    fn build_portfolio() -> Portfolio {
+        let mut p = Portfolio::new();
+        p.mode = Some(Mode::Optimize);
+        let mut taxed = Account::new("taxed");
+        taxed.cash = dec!(8_000);
+        p.accounts.push(taxed);
+        let mut ira = Account::new("ira");
+        ira.cash = dec!(2_000);
+        ira.tax_sheltered = true;
+        p.accounts.push(ira);
+        p.target.insert(String::from("A"), 0.5.into());
+        p.target.insert(String::from("B"), 0.5.into());
+        let mut a = Investment::new("A", dec!(10));
+        a.div_yield = Some(0.05);
+        p.market.push(a);
+        p.market.push(Investment::new("B", dec!(100)));
+        p
+    }
+
+    #[test]
+    fn optimize_cash() {
+        let r = run_balancing(build_portfolio());
+
+        assert_that(&r.total_cash).is_equal_to(dec!(0));
+        check_shares(&r, "taxed", "A", dec!(300));
+        check_shares(&r, "taxed", "B", dec!(50));
+        check_shares(&r, "ira", "A", dec!(200));
+        check_shares(&r, "ira", "B", dec!(0));
+        // one order per fund and account, no more
+        assert_that(&r.trades).has_length(3);
+    }
+
+    #[test]
+    fn optimize_large_balances() {
+        let mut p = Portfolio::new();
+        p.mode = Some(Mode::Optimize);
+        let mut acct = Account::new("taxed");
+        acct.cash = dec!(1_000_000);
+        p.accounts.push(acct);
+        p.target.insert(String::from("A"), 0.5.into());
+        p.target.insert(String::from("B"), 0.5.into());
+        p.market.push(Investment::new("A", dec!(1)));
+        p.market.push(Investment::new("B", dec!(3)));
+
+        let r = run_balancing(p);
+
+        // another share of either fund would drift further than the $2 left over
+        check_shares(&r, "taxed", "A", dec!(500_000));
+        check_shares(&r, "taxed", "B", dec!(166_666));
+        assert_that(&r.total_cash).is_equal_to(dec!(2));
+        assert_that(&r.trades).has_length(2);
+    }
+
+    #[test]
+    fn optimize_with_sales() {
+        let mut p = build_portfolio();
+        p.accounts[0].cash = dec!(0);
+        p.accounts[0]
+            .positions
+            .insert(String::from("B"), dec!(80).into());
+
+        let r = run_balancing(p);
+
+        // sheltered high-yield A, and the sale comes from the taxable account
+        assert_that(&r.total_cash).is_equal_to(dec!(0));
+        check_shares(&r, "ira", "A", dec!(200));
+        check_shares(&r, "taxed", "B", dec!(50));
+        check_shares(&r, "taxed", "A", dec!(300));
+    }
+
+    #[test]
+    fn optimize_respects_no_sales() {
+        let mut p = build_portfolio();
+        p.accounts[0].cash = dec!(0);
+        p.accounts[0]
+            .positions
+            .insert(String::from("B"), dec!(80).into());
+        p.no_sale_accounts.insert(String::from("taxed"));
+
+        let r = run_balancing(p);
+
+        check_shares(&r, "taxed", "B", dec!(80));
+        check_shares(&r, "ira", "A", dec!(200));
+    }
+
+    fn build_gains_portfolio() -> Portfolio {
+        let mut p = build_portfolio();
+        p.as_of = Some(Date::new(2020, 6, 1));
+        p.accounts[0].cash = dec!(0);
+        let lots = vec![Lot::new(Date::new(2020, 1, 1), dec!(80), dec!(10))];
+        p.accounts[0]
+            .positions
+            .insert(String::from("B"), Position::Lots(lots));
+        p
+    }
+
+    #[test]
+    fn optimize_avoids_costly_gains() {
+        let r = run_balancing(build_gains_portfolio());
+        check_shares(&r, "taxed", "B", dec!(50));
+
+        // selling B realizes $90 of gains per share, more than the drift it fixes is worth
+        let mut p = build_gains_portfolio();
+        p.penalties = Some(Penalties {
+            tax: 3.0,
+            ..Penalties::default()
+        });
+        let r = run_balancing(p);
+        check_shares(&r, "taxed", "B", dec!(80));
+        assert_that(&r.gains.is_empty()).is_true();
+    }
+
+    #[test]
+    fn optimize_skips_small_orders() {
+        let build = || {
+            let mut p = Portfolio::new();
+            p.mode = Some(Mode::Optimize);
+            let mut acct = Account::new("taxed");
+            acct.cash = dec!(10);
+            acct.positions.insert(String::from("A"), dec!(499).into());
+            acct.positions.insert(String::from("B"), dec!(50).into());
+            p.accounts.push(acct);
+            p.target.insert(String::from("A"), 0.5.into());
+            p.target.insert(String::from("B"), 0.5.into());
+            p.market.push(Investment::new("A", dec!(10)));
+            p.market.push(Investment::new("B", dec!(100)));
+            p
+        };
+        let r = run_balancing(build());
+        check_shares(&r, "taxed", "A", dec!(500));
+
+        // fixing $10 of drift isn't worth placing an order that costs $1k
+        let mut p = build();
+        p.penalties = Some(Penalties {
+            trade: 1_000.0,
+            ..Penalties::default()
+        });
+        let r = run_balancing(p);
+        assert_that(&r.trades).is_empty();
+        assert_that(&r.total_cash).is_equal_to(dec!(10));
+    }
+
+    #[test]
+    fn optimize_turnover_cap() {
+        let mut p = Portfolio::new();
+        p.mode = Some(Mode::Optimize);
+        let mut acct = Account::new("taxed");
+        acct.positions.insert(String::from("B"), dec!(45).into());
+        acct.positions.insert(String::from("C"), dec!(55).into());
+        p.accounts.push(acct);
+        p.target.insert(String::from("A"), 0.2.into());
+        p.target.insert(String::from("B"), 0.3.into());
+        p.target.insert(String::from("C"), 0.5.into());
+        p.market.push(Investment::new("A", dec!(10)));
+        p.market.push(Investment::new("B", dec!(100)));
+        p.market.push(Investment::new("C", dec!(100)));
+        p.max_turnover = Some(10.0);
+
+        let r = run_balancing(p);
+
+        // only $1k of the $2k that's overweight can be sold
+        assert_that(&r.turnover).is_close_to(0.1, 0.0001);
+        check_shares(&r, "taxed", "A", dec!(100));
+        let capped: f32 = r.capped_drift.values().sum();
+        assert_that(&capped).is_close_to(0.1, 0.0001);
+    }
+}
@@ -0,0 +1,140 @@
+use std::collections::BTreeMap;
+
+/// A target allocation, either directly for an asset class or fund, or for a group that's
+/// split between its children. Weights are fractions of the parent.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Target {
+    Weight(f32),
+    Group {
+        weight: f32,
+        children: BTreeMap<String, Target>,
+    },
+}
+
+impl Target {
+    pub fn weight(&self) -> f32 {
+        match self {
+            Target::Weight(weight) => *weight,
+            Target::Group { weight, .. } => *weight,
+        }
+    }
+
+    fn children(&self) -> Option<&BTreeMap<String, Target>> {
+        match self {
+            Target::Weight(_) => None,
+            Target::Group { children, .. } => Some(children),
+        }
+    }
+}
+
+impl From<f32> for Target {
+    fn from(weight: f32) -> Self {
+        Target::Weight(weight)
+    }
+}
+
+/// A node of the target tree, with its share of the whole portfolio
+#[derive(Debug, Clone, PartialEq)]
+pub struct Node {
+    pub name: String,
+    pub weight: f32,
+    pub leaves: Vec<String>, // the asset classes or funds the node is made of
+    pub children_sum: Option<f32>, // total weight of the children, for groups
+}
+
+/// Every node of the tree, parents before their children
+pub fn nodes(tree: &BTreeMap<String, Target>) -> Vec<Node> {
+    let mut nodes = vec![];
+    for (name, target) in tree.iter() {
+        add_nodes(name, target, 1.0, &mut nodes);
+    }
+    nodes
+}
+
+fn add_nodes(name: &str, target: &Target, parent_weight: f32, nodes: &mut Vec<Node>) {
+    let weight = parent_weight * target.weight();
+    let index = nodes.len();
+    nodes.push(Node {
+        name: name.to_string(),
+        weight,
+        leaves: vec![name.to_string()],
+        children_sum: target
+            .children()
+            .map(|c| c.values().map(|t| t.weight()).sum()),
+    });
+    if let Some(children) = target.children() {
+        for (child, target) in children.iter() {
+            add_nodes(child, target, weight, nodes);
+        }
+        // everything after the group is its subtree
+        nodes[index].leaves = nodes[index + 1..]
+            .iter()
+            .filter(|n| n.children_sum.is_none())
+            .map(|n| n.name.clone())
+            .collect();
+    }
+}
+
+/// Share of the whole portfolio for each asset class or fund at the bottom of the tree
+pub fn flatten(tree: &BTreeMap<String, Target>) -> BTreeMap<String, f32> {
+    nodes(tree)
+        .into_iter()
+        .filter(|n| n.children_sum.is_none())
+        .map(|n| (n.name, n.weight))
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use spectral::prelude::*;
+
+    fn build_tree() -> BTreeMap<String, Target> {
+        serde_json::from_str(
+            r#"{
+                "equity": {"weight": 0.6, "children": {
+                    "us": 0.7,
+                    "intl": {"weight": 0.3, "children": {"developed": 0.8, "emerging": 0.2}}
+                }},
+                "bonds": 0.4
+            }"#,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn flatten_tree() {
+        let flat = flatten(&build_tree());
+
+        assert_that(&flat.len()).is_equal_to(4);
+        assert_that(&flat["us"]).is_close_to(0.42, 0.0001);
+        assert_that(&flat["developed"]).is_close_to(0.144, 0.0001);
+        assert_that(&flat["emerging"]).is_close_to(0.036, 0.0001);
+        assert_that(&flat["bonds"]).is_close_to(0.4, 0.0001);
+    }
+
+    #[test]
+    fn tree_nodes() {
+        let nodes = nodes(&build_tree());
+
+        let names: Vec<&str> = nodes.iter().map(|n| n.name.as_str()).collect();
+        assert_that(&names).is_equal_to(vec![
+            "bonds",
+            "equity",
+            "intl",
+            "developed",
+            "emerging",
+            "us",
+        ]);
+        let equity = &nodes[1];
+        assert_that(&equity.weight).is_close_to(0.6, 0.0001);
+        assert_that(&equity.leaves).is_equal_to(vec![
+            "developed".to_string(),
+            "emerging".to_string(),
+            "us".to_string(),
+        ]);
+        assert_that(&equity.children_sum).is_equal_to(Some(1.0));
+        assert_that(&nodes[0].children_sum).is_none();
+    }
+}
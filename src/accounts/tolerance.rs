@@ -0,0 +1,143 @@
+use super::*;
+
+/// How far a target can drift before it's rebalanced, e.g. the 5/25 rule is
+/// `{"absolute": 5, "relative": 25}`. Either limit can be left out.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Band {
+    absolute: Option<f32>, // percentage points of the portfolio
+    relative: Option<f32>, // percent of the target's own allocation
+}
+
+impl Band {
+    pub fn new(absolute: Option<f32>, relative: Option<f32>) -> Self {
+        Band { absolute, relative }
+    }
+
+    /// The tighter of the two limits for a target, as a fraction of the portfolio
+    fn limit(&self, target: f32) -> Option<(f32, Reason)> {
+        let absolute = self.absolute.map(|a| (a / 100.0, Reason::Absolute));
+        let relative = self
+            .relative
+            .map(|r| (r / 100.0 * target, Reason::Relative));
+        match (absolute, relative) {
+            (Some(a), Some(r)) if r.0 < a.0 => Some(r),
+            (Some(a), _) => Some(a),
+            (None, r) => r,
+        }
+    }
+}
+
+/// Which limit a target was beyond
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Reason {
+    Absolute,
+    Relative,
+}
+
+/// A target that drifted outside its band, and so was traded
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct OutOfBand {
+    symbol: String,
+    allocation: f32,
+    target: f32,
+    drift: f32,
+    limit: f32,
+    reason: Reason,
+}
+
+/// Whether a target should be rebalanced, selling any excess, given the targets found outside
+/// their bands. Targets inside them can still be bought with new cash.
+pub fn should_trade(outside: Option<&[OutOfBand]>, class: &str) -> bool {
+    outside.is_none_or(|o| o.iter().any(|o| o.symbol == class))
+}
+
+/// Finds the targets outside their bands, or `None` if there aren't any bands and every
+/// target should be balanced
+pub fn out_of_band(
+    portfolio: &Portfolio,
+    targets: &BTreeMap<String, f32>,
+    class_values: &HashMap<String, Decimal>,
+    invested: Decimal,
+) -> Option<Vec<OutOfBand>> {
+    if portfolio.tolerance.is_none() && portfolio.tolerances.is_empty() {
+        return None;
+    }
+    let mut classes: Vec<&String> = targets.keys().chain(class_values.keys()).collect();
+    classes.sort();
+    classes.dedup();
+
+    let mut outside = vec![];
+    for class in classes {
+        let target = targets.get(class).cloned().unwrap_or(0.0);
+        let value = class_values.get(class).cloned().unwrap_or_default();
+        let allocation = if invested > Decimal::ZERO {
+            money::fraction(value, invested)
+        } else {
+            0.0
+        };
+        let drift = allocation - target;
+        let band = portfolio
+            .tolerances
+            .get(class)
+            .or(portfolio.tolerance.as_ref());
+        let (limit, reason) = match band.and_then(|b| b.limit(target)) {
+            Some(limit) => limit,
+            None => (0.0, Reason::Absolute), // no band, any drift at all is out of it
+        };
+        if drift.abs() > limit {
+            outside.push(OutOfBand {
+                symbol: class.clone(),
+                allocation,
+                target,
+                drift,
+                limit,
+                reason,
+            });
+        }
+    }
+    Some(outside)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use spectral::prelude::*;
+
+    #[test]
+    fn band_limits() {
+        let band = Band::new(Some(5.0), Some(25.0));
+        // 25% of a 10% target is tighter than 5 points
+        assert_that(&band.limit(0.1)).is_equal_to(Some((0.025, Reason::Relative)));
+        assert_that(&band.limit(0.6)).is_equal_to(Some((0.05, Reason::Absolute)));
+        assert_that(&Band::new(None, Some(10.0)).limit(0.5))
+            .is_equal_to(Some((0.05, Reason::Relative)));
+        assert_that(&Band::default().limit(0.5)).is_none();
+    }
+
+    #[test]
+    fn find_out_of_band() {
+        let mut p = Portfolio::new();
+        let targets = [("A", 0.6), ("B", 0.3), ("C", 0.1)]
+            .iter()
+            .map(|(s, w)| (s.to_string(), *w))
+            .collect();
+        let values = c! { s.to_string() => v,
+        for (s, v) in [("A", dec!(640)), ("B", dec!(260)), ("C", dec!(110))] };
+        assert_that(&out_of_band(&p, &targets, &values, dec!(1000))).is_none();
+
+        p.tolerance = Some(Band::new(Some(5.0), Some(25.0)));
+        p.tolerances
+            .insert(String::from("C"), Band::new(Some(0.5), None));
+        let outside = out_of_band(&p, &targets, &values, dec!(1000)).unwrap();
+
+        // A and B are 4 points off, within 5, but C is a point over its half point limit
+        let symbols: Vec<&str> = outside.iter().map(|o| o.symbol.as_str()).collect();
+        assert_that(&symbols).is_equal_to(vec!["C"]);
+        assert_that(&outside[0].drift).is_close_to(0.01, 0.0001);
+        assert_that(&outside[0].reason).is_equal_to(Reason::Absolute);
+        assert_that(&should_trade(Some(&outside), "C")).is_true();
+        assert_that(&should_trade(Some(&outside), "A")).is_false();
+        assert_that(&should_trade(None, "A")).is_true();
+    }
+}
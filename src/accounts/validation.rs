@@ -0,0 +1,263 @@
+use serde::ser::{Serialize, SerializeStruct, Serializer};
+use std::fmt;
+
+/// A problem with a submitted portfolio, serialized with a stable error code so
+/// callers can react to specific failures
+#[derive(Debug, Clone, PartialEq)]
+pub enum ValidationError {
+    AllocationSum { sum: f32 },
+    GroupSum { group: String, sum: f32 },
+    DuplicateTargets { symbols: Vec<String> },
+    MissingPrices { symbols: Vec<String> },
+    InvalidPrices { symbols: Vec<String> },
+    InvalidLots { symbols: Vec<String> },
+    OverlappingClasses { symbols: Vec<String> },
+    EmptyClasses { symbols: Vec<String> },
+    DuplicateAccounts { accounts: Vec<String> },
+    UnknownAccounts { accounts: Vec<String> },
+    InvalidReserves { accounts: Vec<String> },
+    InvalidDeposits { accounts: Vec<String> },
+    InvalidWithdrawal { amount: f32 },
+    InvalidDistributions { accounts: Vec<String> },
+    InvalidTaxRates,
+    InvalidCommissions { accounts: Vec<String> },
+    InvalidShareIncrement { accounts: Vec<String> },
+    InvalidTurnover { percent: f32 },
+    InvalidQuotes { symbols: Vec<String> },
+}
+
+impl ValidationError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            ValidationError::AllocationSum { .. } => "allocation_sum",
+            ValidationError::GroupSum { .. } => "group_sum",
+            ValidationError::DuplicateTargets { .. } => "duplicate_targets",
+            ValidationError::MissingPrices { .. } => "missing_prices",
+            ValidationError::InvalidPrices { .. } => "invalid_prices",
+            ValidationError::InvalidLots { .. } => "invalid_lots",
+            ValidationError::OverlappingClasses { .. } => "overlapping_classes",
+            ValidationError::EmptyClasses { .. } => "empty_classes",
+            ValidationError::DuplicateAccounts { .. } => "duplicate_accounts",
+            ValidationError::UnknownAccounts { .. } => "unknown_accounts",
+            ValidationError::InvalidReserves { .. } => "invalid_reserves",
+            ValidationError::InvalidDeposits { .. } => "invalid_deposits",
+            ValidationError::InvalidWithdrawal { .. } => "invalid_withdrawal",
+            ValidationError::InvalidDistributions { .. } => "invalid_distributions",
+            ValidationError::InvalidTaxRates => "invalid_tax_rates",
+            ValidationError::InvalidCommissions { .. } => "invalid_commissions",
+            ValidationError::InvalidShareIncrement { .. } => "invalid_share_increment",
+            ValidationError::InvalidTurnover { .. } => "invalid_turnover",
+            ValidationError::InvalidQuotes { .. } => "invalid_quotes",
+        }
+    }
+
+    fn symbols(&self) -> &[String] {
+        match self {
+            ValidationError::GroupSum { group, .. } => std::slice::from_ref(group),
+            ValidationError::DuplicateTargets { symbols } => symbols,
+            ValidationError::MissingPrices { symbols } => symbols,
+            ValidationError::InvalidPrices { symbols } => symbols,
+            ValidationError::InvalidLots { symbols } => symbols,
+            ValidationError::OverlappingClasses { symbols } => symbols,
+            ValidationError::EmptyClasses { symbols } => symbols,
+            ValidationError::InvalidQuotes { symbols } => symbols,
+            _ => &[],
+        }
+    }
+
+    fn accounts(&self) -> &[String] {
+        match self {
+            ValidationError::DuplicateAccounts { accounts } => accounts,
+            ValidationError::UnknownAccounts { accounts } => accounts,
+            ValidationError::InvalidReserves { accounts } => accounts,
+            ValidationError::InvalidDeposits { accounts } => accounts,
+            ValidationError::InvalidDistributions { accounts } => accounts,
+            ValidationError::InvalidCommissions { accounts } => accounts,
+            ValidationError::InvalidShareIncrement { accounts } => accounts,
+            _ => &[],
+        }
+    }
+
+    fn value(&self) -> Option<f32> {
+        match self {
+            ValidationError::AllocationSum { sum } => Some(*sum),
+            ValidationError::GroupSum { sum, .. } => Some(*sum),
+            ValidationError::InvalidWithdrawal { amount } => Some(*amount),
+            ValidationError::InvalidTurnover { percent } => Some(*percent),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ValidationError::AllocationSum { sum } => {
+                write!(f, "Allocations must add up to 1.0, got {}", sum)
+            }
+            ValidationError::GroupSum { group, sum } => write!(
+                f,
+                "Allocations within {} must add up to 1.0, got {}",
+                group, sum
+            ),
+            ValidationError::DuplicateTargets { symbols } => write!(
+                f,
+                "Targets can only appear once in the tree: {}",
+                symbols.join(", ")
+            ),
+            ValidationError::MissingPrices { symbols } => {
+                write!(f, "Missing prices for {}", symbols.join(", "))
+            }
+            ValidationError::InvalidPrices { symbols } => {
+                write!(f, "Prices must be positive for {}", symbols.join(", "))
+            }
+            ValidationError::InvalidLots { symbols } => write!(
+                f,
+                "Lots must have non-negative shares and cost basis for {}",
+                symbols.join(", ")
+            ),
+            ValidationError::OverlappingClasses { symbols } => write!(
+                f,
+                "Funds can only belong to one asset class: {}",
+                symbols.join(", ")
+            ),
+            ValidationError::EmptyClasses { symbols } => write!(
+                f,
+                "Asset classes need at least one fund: {}",
+                symbols.join(", ")
+            ),
+            ValidationError::DuplicateAccounts { accounts } => {
+                write!(f, "Account names must be unique: {}", accounts.join(", "))
+            }
+            ValidationError::UnknownAccounts { accounts } => {
+                write!(f, "Unknown accounts referenced: {}", accounts.join(", "))
+            }
+            ValidationError::InvalidReserves { accounts } => write!(
+                f,
+                "Cash reserves must be non-negative dollars or 0-100 percent for {}",
+                accounts.join(", ")
+            ),
+            ValidationError::InvalidDeposits { accounts } => {
+                write!(
+                    f,
+                    "Deposits must be non-negative for {}",
+                    accounts.join(", ")
+                )
+            }
+            ValidationError::InvalidWithdrawal { amount } => {
+                write!(f, "Withdrawals must be positive, got {}", amount)
+            }
+            ValidationError::InvalidDistributions { accounts } => write!(
+                f,
+                "Required distributions must be positive and from traditional accounts for {}",
+                accounts.join(", ")
+            ),
+            ValidationError::InvalidTaxRates => {
+                write!(f, "Tax rates must be between 0 and 1")
+            }
+            ValidationError::InvalidCommissions { accounts } => write!(
+                f,
+                "Commissions must be non-negative for {}",
+                accounts.join(", ")
+            ),
+            ValidationError::InvalidShareIncrement { accounts } => write!(
+                f,
+                "Share increments must be more than 0 and at most 1 for {}",
+                accounts.join(", ")
+            ),
+            ValidationError::InvalidTurnover { percent } => {
+                write!(f, "Turnover cap must be 0-100 percent, got {}", percent)
+            }
+            ValidationError::InvalidQuotes { symbols } => write!(
+                f,
+                "Bids and asks must be positive, with the bid no higher than the ask, for {}",
+                symbols.join(", ")
+            ),
+        }
+    }
+}
+
+impl Serialize for ValidationError {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut s = serializer.serialize_struct("ValidationError", 5)?;
+        s.serialize_field("code", self.code())?;
+        s.serialize_field("message", &self.to_string())?;
+        s.serialize_field("symbols", self.symbols())?;
+        s.serialize_field("accounts", self.accounts())?;
+        s.serialize_field("value", &self.value())?;
+        s.end()
+    }
+}
+
+/// Every problem found with a portfolio, in the order they were checked
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct ValidationErrors {
+    errors: Vec<ValidationError>,
+}
+
+impl ValidationErrors {
+    pub fn errors(&self) -> &[ValidationError] {
+        &self.errors
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.errors.is_empty()
+    }
+
+    /// Records a problem with the given names, skipping it entirely if there were none
+    pub fn push_names<F>(&mut self, mut names: Vec<String>, error: F)
+    where
+        F: FnOnce(Vec<String>) -> ValidationError,
+    {
+        if names.is_empty() {
+            return;
+        }
+        names.sort();
+        names.dedup();
+        self.errors.push(error(names));
+    }
+
+    pub fn push(&mut self, error: ValidationError) {
+        self.errors.push(error);
+    }
+}
+
+impl fmt::Display for ValidationErrors {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let messages: Vec<String> = self.errors.iter().map(|e| e.to_string()).collect();
+        write!(f, "{}", messages.join("; "))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use spectral::prelude::*;
+
+    #[test]
+    fn collects_named_errors() {
+        let mut errors = ValidationErrors::default();
+        errors.push_names(vec![], |symbols| ValidationError::MissingPrices { symbols });
+        assert!(errors.is_empty());
+
+        let names = vec!["B".to_string(), "A".to_string(), "B".to_string()];
+        errors.push_names(names, |symbols| ValidationError::MissingPrices { symbols });
+        errors.push(ValidationError::AllocationSum { sum: 0.5 });
+
+        assert_that(&errors.errors().len()).is_equal_to(2);
+        assert_that(&errors.to_string()).is_equal_to(
+            "Missing prices for A, B; Allocations must add up to 1.0, got 0.5".to_string(),
+        );
+    }
+
+    #[test]
+    fn error_details() {
+        let e = ValidationError::UnknownAccounts {
+            accounts: vec!["ira".to_string()],
+        };
+        assert_that(&e.code()).is_equal_to("unknown_accounts");
+        assert_that(&e.accounts().to_vec()).has_length(1);
+        assert_that(&e.symbols().to_vec()).has_length(0);
+        assert_that(&e.value()).is_none();
+    }
+}
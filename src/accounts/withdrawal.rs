@@ -0,0 +1,308 @@
+use super::*;
+use std::cmp::Ordering;
+
+/// Cash to take out of the portfolio, instead of balancing it
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Withdrawal {
+    amount: Decimal,
+    #[serde(default)]
+    accounts: Vec<String>, // defaults to raising the cash in any account
+}
+
+impl Withdrawal {
+    pub fn new(amount: Decimal, accounts: &[&str]) -> Self {
+        Withdrawal {
+            amount,
+            accounts: accounts.iter().map(|a| a.to_string()).collect(),
+        }
+    }
+
+    pub fn amount(&self) -> Decimal {
+        self.amount
+    }
+
+    pub fn is_valid(&self) -> bool {
+        self.amount > Decimal::ZERO
+    }
+
+    pub fn accounts(&self) -> &[String] {
+        &self.accounts
+    }
+
+    /// Whether the cash can come out of the account
+    pub fn from_account(&self, account: &str) -> bool {
+        self.accounts.is_empty() || self.accounts.iter().any(|a| a == account)
+    }
+}
+
+/// Sells from the given accounts, which should be in the order to sell from, until they have
+/// `amount` of cash and then takes it out. Each sale comes from whichever target is furthest
+/// over where it should be once the money is gone, following the same sale rules as balancing.
+/// `required` cash, like a required distribution, is raised even when only deposits are being
+/// invested.
+pub fn raise_cash(
+    portfolio: &Portfolio,
+    results: &mut Results,
+    prices: &HashMap<&String, Decimal>,
+    accounts: &[&Account],
+    amount: Decimal,
+    required: bool,
+) {
+    let targets = portfolio.targets();
+    let remaining_value = portfolio.investable_value() - results.withdrawn() - amount;
+    let cash =
+        |r: &Results| -> Decimal { accounts.iter().map(|a| r.available_cash(&a.name)).sum() };
+    let sold_before = results.sold();
+
+    while cash(results) < amount {
+        let short = amount - cash(results);
+        let mut overweight: Vec<(String, Decimal, f32)> = results
+            .class_values(prices)
+            .into_iter()
+            .map(|(class, value)| {
+                let weight = targets.get(&class).cloned().unwrap_or(0.0);
+                let target = money::decimal(weight) * remaining_value;
+                let excess = value - target;
+                let ratio = if target > Decimal::ZERO {
+                    money::fraction(excess, target)
+                } else {
+                    f32::INFINITY
+                };
+                (class, excess, ratio)
+            })
+            .collect();
+        // most overweight first, relative to the target like the buy phase
+        overweight.sort_by(|(a, _, a_ratio), (b, _, b_ratio)| {
+            b_ratio
+                .partial_cmp(a_ratio)
+                .unwrap_or(Ordering::Equal)
+                .then_with(|| a.cmp(b))
+        });
+
+        let sold = overweight.iter().any(|(class, excess, _)| {
+            let value = if *excess > Decimal::ZERO {
+                (*excess).min(short)
+            } else {
+                short
+            };
+            accounts
+                .iter()
+                .filter(|a| {
+                    if required {
+                        portfolio.allows_required_sales(a)
+                    } else {
+                        portfolio.allows_sales(a)
+                    }
+                })
+                .any(|account| sell_value(portfolio, results, prices, account, class, value))
+        });
+        if !sold {
+            println!("only able to raise {} of {}", cash(results), amount);
+            break;
+        }
+    }
+    // the sales pay for the withdrawal, so they don't count towards the turnover cap
+    results.raised += results.sold() - sold_before;
+
+    let mut remaining = amount;
+    for account in accounts.iter() {
+        let taken = results.available_cash(&account.name).min(remaining);
+        if taken <= Decimal::ZERO {
+            continue;
+        }
+        results.cash(&account.name, -taken);
+        *results.withdrawals.entry(account.name.clone()).or_default() += taken;
+        remaining -= taken;
+    }
+}
+
+/// Sells at least `value` of the first fund in the class held in the account, if it can
+fn sell_value(
+    portfolio: &Portfolio,
+    results: &mut Results,
+    prices: &HashMap<&String, Decimal>,
+    account: &Account,
+    class: &str,
+    value: Decimal,
+) -> bool {
+    let increment = account.share_increment();
+    for sym in portfolio.members(class) {
+        // other funds in the class might not have been priced if they aren't held
+        let price = match prices.get(&sym.to_string()) {
+            Some(price) => *price,
+            None => continue,
+        };
+        let bid = results.fill_price(sym, price, -Decimal::ONE);
+        let held = results.transact(&account.name, sym, Decimal::ZERO);
+        // round up, so the cash raised at the bid covers the value
+        let wanted = (value / bid / increment).ceil() * increment;
+        let to_sell = account.tradeable(wanted.min(held));
+        let to_sell = results.sellable(&account.name, sym, bid, to_sell);
+        if to_sell < increment {
+            continue;
+        }
+        if results
+            .buy_maybe(&account.name, sym, price, -to_sell)
+            .is_some()
+        {
+            println!(
+                "withdrawal: acct={} sold {} x {}@{}",
+                account.name, to_sell, sym, bid
+            );
+            return true;
+        }
+    }
+    false
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::accounts::test::check_shares;
+    use crate::run_balancing;
+    use spectral::prelude::*;
+
+    fn build_portfolio() -> Portfolio {
+        let mut p = Portfolio::new();
+        let mut taxed = Account::new("taxed");
+        taxed.positions.insert(String::from("A"), dec!(500).into());
+        taxed.positions.insert(String::from("B"), dec!(40).into());
+        p.accounts.push(taxed);
+        let mut ira = Account::new("ira");
+        ira.tax_sheltered = true;
+        ira.positions.insert(String::from("B"), dec!(20).into());
+        p.accounts.push(ira);
+        p.target.insert(String::from("A"), 0.5.into());
+        p.target.insert(String::from("B"), 0.5.into());
+        p.market.push(Investment::new("A", dec!(10)));
+        p.market.push(Investment::new("B", dec!(100)));
+        p
+    }
+
+    #[test]
+    fn withdraw_from_overweight() {
+        let mut p = build_portfolio();
+        p.withdrawal = Some(Withdrawal::new(dec!(2_000), &[]));
+
+        let r = run_balancing(p);
+
+        // B is $1k over, so once it's even with A the rest comes half from each
+        assert_that(&r.withdrawals.values().sum::<Decimal>()).is_equal_to(dec!(2_000));
+        assert_that(&r.total_cash).is_equal_to(dec!(0));
+        check_shares(&r, "ira", "B", dec!(5));
+        check_shares(&r, "taxed", "B", dec!(40));
+        check_shares(&r, "taxed", "A", dec!(450));
+        assert_that(&r.drift["A"]).is_close_to(0.0, 0.001);
+    }
+
+    #[test]
+    fn withdraw_from_accounts() {
+        let mut p = build_portfolio();
+        p.withdrawal = Some(Withdrawal::new(dec!(1_500), &["taxed"]));
+        p.no_sale_accounts.insert(String::from("ira"));
+
+        let r = run_balancing(p);
+
+        assert_that(&r.withdrawals["taxed"]).is_equal_to(dec!(1_500));
+        assert_that(&r.withdrawals.get("ira")).is_none();
+        check_shares(&r, "ira", "B", dec!(20));
+        check_shares(&r, "taxed", "B", dec!(27));
+        check_shares(&r, "taxed", "A", dec!(480));
+    }
+
+    #[test]
+    fn withdraw_what_can_be_sold() {
+        let mut p = build_portfolio();
+        p.withdrawal = Some(Withdrawal::new(dec!(1_000), &["taxed"]));
+        p.no_sale_accounts.insert(String::from("taxed"));
+        p.accounts[0].cash = dec!(300);
+
+        let r = run_balancing(p);
+
+        assert_that(&r.withdrawals["taxed"]).is_equal_to(dec!(300));
+        assert_that(&r.trades).is_empty();
+    }
+
+    #[test]
+    fn withdraw_at_the_bid() {
+        let mut p = build_portfolio();
+        p.market[0].bid = Some(dec!(9));
+        p.market[1].bid = Some(dec!(90));
+        p.withdrawal = Some(Withdrawal::new(dec!(1_000), &[]));
+
+        let r = run_balancing(p);
+
+        // 12 shares of B at the bid cover it in one sale, where 10 at the price would fall short
+        assert_that(&r.withdrawals["ira"]).is_equal_to(dec!(1_000));
+        assert_that(&r.trades).has_length(1);
+        assert_that(&r.trades[0].shares).is_equal_to(dec!(12));
+        assert_that(&r.total_cash).is_less_than(dec!(90));
+    }
+
+    #[test]
+    fn withdraw_skips_unpriced_funds() {
+        let mut p = build_portfolio();
+        p.classes.insert(
+            String::from("intl"),
+            vec![String::from("VXUS"), String::from("IXUS")],
+        );
+        p.accounts[0]
+            .positions
+            .insert(String::from("VXUS"), dec!(10).into());
+        p.market.push(Investment::new("VXUS", dec!(50)));
+        p.withdrawal = Some(Withdrawal::new(dec!(100), &[]));
+
+        let r = run_balancing(p);
+
+        // the IRA has no VXUS to sell, and IXUS isn't held anywhere so it has no price
+        assert_that(&r.withdrawals.values().sum::<Decimal>()).is_equal_to(dec!(100));
+    }
+
+    #[test]
+    fn required_distribution() {
+        let mut p = Portfolio::new();
+        let mut taxed = Account::new("taxed");
+        taxed.positions.insert(String::from("B"), dec!(30).into());
+        p.accounts.push(taxed);
+        let mut ira = Account::new("ira");
+        ira.account_type = Some(AccountType::Traditional);
+        ira.positions.insert(String::from("A"), dec!(200).into());
+        ira.positions.insert(String::from("B"), dec!(10).into());
+        ira.required_distribution = Some(dec!(2_000));
+        p.accounts.push(ira);
+        p.target.insert(String::from("A"), 0.5.into());
+        p.target.insert(String::from("B"), 0.5.into());
+        p.market.push(Investment::new("A", dec!(10)));
+        p.market.push(Investment::new("B", dec!(100)));
+
+        let r = run_balancing(p);
+
+        // the IRA sells all its B and then A for the RMD, so taxed swaps B for A to make up
+        assert_that(&r.withdrawals["ira"]).is_equal_to(dec!(2_000));
+        assert_that(&r.withdrawals.get("taxed")).is_none();
+        check_shares(&r, "ira", "B", dec!(0));
+        check_shares(&r, "ira", "A", dec!(100));
+        check_shares(&r, "taxed", "B", dec!(20));
+        check_shares(&r, "taxed", "A", dec!(100));
+        assert_that(&r.drift["A"]).is_close_to(0.0, 0.001);
+    }
+
+    #[test]
+    fn required_distribution_with_deposits_only() {
+        let mut p = Portfolio::new();
+        let mut ira = Account::new("ira");
+        ira.account_type = Some(AccountType::Traditional);
+        ira.positions.insert(String::from("A"), dec!(200).into());
+        ira.required_distribution = Some(dec!(500));
+        p.accounts.push(ira);
+        p.target.insert(String::from("A"), 1.0.into());
+        p.market.push(Investment::new("A", dec!(10)));
+        p.deposits = Some(HashMap::new());
+
+        let r = run_balancing(p);
+
+        // the distribution still has to come out, even though nothing else is sold
+        assert_that(&r.withdrawals["ira"]).is_equal_to(dec!(500));
+        check_shares(&r, "ira", "A", dec!(150));
+    }
+}
@@ -6,6 +6,8 @@ extern crate cute;
 
 extern crate stats;
 
+#[cfg(test)]
+extern crate serde_json;
 #[cfg(test)]
 extern crate spectral;
 
@@ -13,7 +13,7 @@ async fn index() -> impl Responder {
 async fn balance(accounts: web::Json<Portfolio>) -> impl Responder {
     match accounts.validate() {
         None => HttpResponse::Ok().json(run_balancing(accounts.into_inner())),
-        Some(err) => HttpResponse::BadRequest().json(err),
+        Some(errors) => HttpResponse::BadRequest().json(errors),
     }
 }
 